@@ -0,0 +1,177 @@
+// Compares the config handling the accept loop does for each connection
+// before and after snapshots: cloning the whole vhost map and then the vhost
+// against loading the current snapshot and taking a reference to the vhost.
+// Run with `cargo bench`.
+#![allow(dead_code, unused_imports, special_module_name)]
+#[macro_use]
+extern crate serde_derive;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+// The server's modules are built into the bench so it measures the real
+// config types.
+#[path = "../src/access.rs"]
+mod access;
+#[path = "../src/ban.rs"]
+mod ban;
+#[path = "../src/cache.rs"]
+mod cache;
+#[cfg(any(feature = "cgi", feature = "scgi"))]
+#[path = "../src/cgi.rs"]
+mod cgi;
+#[path = "../src/con_handler.rs"]
+mod con_handler;
+#[path = "../src/config.rs"]
+mod config;
+#[path = "../src/dirlist.rs"]
+mod dirlist;
+#[path = "../src/feed.rs"]
+mod feed;
+#[path = "../src/include.rs"]
+mod include;
+#[path = "../src/lib/mod.rs"]
+mod lib;
+#[path = "../src/logger.rs"]
+mod logger;
+#[cfg(feature = "markdown")]
+#[path = "../src/markdown.rs"]
+mod markdown;
+#[path = "../src/overrides.rs"]
+mod overrides;
+#[path = "../src/ratelimit.rs"]
+mod ratelimit;
+#[cfg(feature = "proxy")]
+#[path = "../src/revproxy.rs"]
+mod revproxy;
+#[path = "../src/template.rs"]
+mod template;
+#[path = "../src/vfs.rs"]
+mod vfs;
+
+use lib::conn;
+use lib::errors;
+use lib::proxy_protocol;
+use lib::server;
+use lib::status;
+use lib::table;
+use lib::tls;
+use lib::util;
+
+// Counts every allocation. The bench runs on a single thread so nothing else
+// adds to the count while it's measuring.
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const CONNECTIONS: u32 = 100_000;
+
+fn config() -> config::Config {
+    let mut toml = String::from("interface = [ \"127.0.0.1:1965\" ]\n");
+    for i in 0..20 {
+        toml.push_str(&format!(
+            r#"
+            [[server]]
+            hostname = "{}.example.com"
+            dir = "/srv/gemini/{}"
+            key = "key.pem"
+            cert = "cert.pem"
+            redirect = {{ "/a" = "/b", "/c" = "/d", "/e" = "/f" }}
+            proxy = {{ "app" = "localhost:1966" }}
+            scgi = {{ "/scgi" = "localhost:4000" }}
+            cgienv = {{ "GEMINI_ENV" = "bench" }}
+            ratelimit_path = {{ "/cgi-bin/" = {{ rate = 0.2, burst = 5 }} }}
+            access_path = {{ "/private/" = {{ allow = [ "10.0.0.0/8" ] }} }}
+            "#,
+            i, i
+        ));
+    }
+    toml::from_str(&toml).unwrap()
+}
+
+// No handshake is made so no certificate is needed.
+struct NoCerts;
+
+impl rustls::server::ResolvesServerCert for NoCerts {
+    fn resolve(&self, _: rustls::server::ClientHello) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        None
+    }
+}
+
+fn acceptor() -> TlsAcceptor {
+    let tls = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(NoCerts));
+    TlsAcceptor::from(Arc::new(tls))
+}
+
+// Allocations and time per connection.
+fn measure<F: FnMut()>(mut connection: F) -> (f64, Duration) {
+    // Anything set up on first use isn't counted.
+    connection();
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..CONNECTIONS {
+        connection();
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCS.load(Ordering::Relaxed) - allocs;
+    (allocs as f64 / CONNECTIONS as f64, elapsed / CONNECTIONS)
+}
+
+fn main() {
+    let cfg = config();
+    let shared = config::Shared::default();
+    let sni = "10.example.com";
+
+    // Before: the accept loop cloned the vhost map for each connection and
+    // the connection cloned its vhost out of it.
+    let cmap: HashMap<String, config::ServerCfg> = cfg
+        .to_map(&shared)
+        .into_iter()
+        .map(|(host, srv)| (host, (*srv).clone()))
+        .collect();
+    let before = measure(|| {
+        let cmap = black_box(&cmap).clone();
+        black_box(cmap.get(sni).unwrap().to_owned());
+    });
+
+    // After: the accept loop loads the current snapshot and the connection
+    // takes a reference to its vhost.
+    let snapshot = Arc::new(ArcSwap::from_pointee(server::Snapshot {
+        cmap: cfg.to_map(&shared),
+        acceptor: acceptor(),
+    }));
+    let after = measure(|| {
+        let snapshot = black_box(&snapshot).load_full();
+        black_box(snapshot.vhost(sni).unwrap());
+    });
+
+    println!("{} vhosts, per connection:", cfg.server.len());
+    println!("cmap.clone(): {:.1} allocations, {:?}", before.0, before.1);
+    println!("snapshot: {:.1} allocations, {:?}", after.0, after.1);
+    assert_eq!(after.0, 0.0);
+}
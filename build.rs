@@ -0,0 +1,75 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Path inside the capsule, whether it's a directory, mode, mtime and the file
+// it's read from.
+type Entry = (String, bool, u32, u64, PathBuf);
+
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut files: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    files.sort_by_key(|f| f.file_name());
+    for file in files {
+        let path = file.path();
+        let rel = match path.strip_prefix(root).ok().and_then(|p| p.to_str()) {
+            Some(r) => r.to_string(),
+            None => {
+                println!("cargo:warning=Skipping {}", path.display());
+                continue;
+            }
+        };
+        let m = fs::metadata(&path)?;
+        let mtime = m
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        entries.push((rel, m.is_dir(), m.permissions().mode(), mtime, path.clone()));
+        if m.is_dir() {
+            walk(root, &path, entries)?;
+        } else {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    Ok(())
+}
+
+// With the embed feature the directory named by GEMSERV_EMBED is compiled into
+// the binary and served for dir = "embedded:". Without GEMSERV_EMBED an empty
+// capsule is embedded so builds with every feature still work.
+fn main() {
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed=GEMSERV_EMBED");
+    let mut entries = Vec::new();
+    match env::var("GEMSERV_EMBED") {
+        Ok(dir) => {
+            let root = fs::canonicalize(&dir).unwrap_or_else(|e| panic!("{}: {}", dir, e));
+            if let Err(e) = walk(&root, &root, &mut entries) {
+                panic!("{}: {}", root.display(), e);
+            }
+        }
+        Err(_) => println!("cargo:warning=GEMSERV_EMBED isn't set, embedding an empty capsule"),
+    }
+
+    let mut out = String::from("pub static FILES: &[(&str, bool, u32, u64, &[u8])] = &[\n");
+    for (rel, dir, mode, mtime, path) in entries {
+        let data = if dir {
+            "&[]".to_string()
+        } else {
+            format!("include_bytes!({:?})", path)
+        };
+        out.push_str(&format!(
+            "    ({:?}, {}, {:#o}, {}, {}),\n",
+            rel, dir, mode, mtime, data
+        ));
+    }
+    out.push_str("];\n");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("embedded.rs"), out).unwrap();
+}
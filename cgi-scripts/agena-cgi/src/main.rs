@@ -1,7 +1,7 @@
 use std::env;
 
 // Base url of agena proxy
-const BASE: &'static str = "gemini://example.com/";
+const BASE: &str = "gemini://example.com/";
 
 fn main() {
     let query = match env::var("QUERY_STRING") {
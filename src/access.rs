@@ -0,0 +1,163 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use crate::config;
+use crate::conn;
+use crate::status::Status;
+use crate::util;
+
+// An address block like "192.0.2.0/24" or "2001:db8::/32". A bare address
+// matches only itself.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = (bits - prefix) as u32;
+    net >> shift == ip >> shift
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in \"{}\"", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= bits => p,
+                _ => return Err(format!("invalid prefix length in \"{}\"", s)),
+            },
+            None => bits,
+        };
+        // Mapped addresses are matched as plain IPv4.
+        let canonical = addr.to_canonical();
+        if canonical.is_ipv4() && addr.is_ipv6() {
+            if prefix < 96 {
+                return Err(format!(
+                    "prefix too short for a mapped address in \"{}\"",
+                    s
+                ));
+            }
+            return Ok(Cidr {
+                addr: canonical,
+                prefix: prefix - 96,
+            });
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+// A client is denied if it matches a deny entry, or if there's an allow list
+// and it doesn't match any entry in it.
+pub fn denied(ip: IpAddr, allow: &Option<Vec<Cidr>>, deny: &Option<Vec<Cidr>>) -> bool {
+    if let Some(d) = deny {
+        if d.iter().any(|c| c.contains(ip)) {
+            return true;
+        }
+    }
+    match allow {
+        Some(a) => !a.iter().any(|c| c.contains(ip)),
+        None => false,
+    }
+}
+
+fn status(code: Option<u8>) -> Status {
+    code.and_then(Status::from_u8).unwrap_or(Status::NotFound)
+}
+
+// Check the server's lists and then the longest matching path rule. Returns
+// the status to send if the client isn't allowed.
+pub fn check(con: &conn::Connection, url: &url::Url) -> Option<Status> {
+    check_ip(&con.srv.server, con.peer_addr.ip(), &util::rule_path(url))
+}
+
+// Check the path rules against the path of the file a request was served
+// from, which differs from the URL's when an extension or index file was
+// filled in.
+pub fn check_path(con: &conn::Connection, path: &str) -> Option<Status> {
+    check_ip(&con.srv.server, con.peer_addr.ip(), path)
+}
+
+fn check_ip(srv: &config::Server, ip: IpAddr, path: &str) -> Option<Status> {
+    if denied(ip, &srv.allow, &srv.deny) {
+        return Some(status(srv.deny_status));
+    }
+
+    let (_, rule) = util::path_rule(srv.access_path.as_ref(), path)?;
+    if denied(ip, &rule.allow, &rule.deny) {
+        return Some(status(rule.status.or(srv.deny_status)));
+    }
+    None
+}
+
+// Only failure statuses make sense for a denied client.
+pub fn validate(srv: &config::Server) -> bool {
+    let mut codes: Vec<u8> = srv.deny_status.into_iter().collect();
+    if let Some(rules) = &srv.access_path {
+        codes.extend(rules.values().filter_map(|r| r.status));
+    }
+    codes.into_iter().all(valid_status)
+}
+
+pub fn valid_status(code: u8) -> bool {
+    (40..60).contains(&code) && Status::from_u8(code).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(settings: &str, ip: &str, path: &str) -> Option<u8> {
+        let srv = config::example(settings).server.remove(0);
+        check_ip(&srv, ip.parse().unwrap(), path).map(|s| s as u8)
+    }
+
+    #[test]
+    fn lists_and_path_rules() {
+        let rules = r#"
+            deny = [ "192.0.2.0/24" ]
+            deny_status = 53
+            access_path = { "/private/" = { allow = [ "10.0.0.0/8" ], status = 51 }, "/private/open/" = {} }
+        "#;
+        assert_eq!(code(rules, "192.0.2.1", "/"), Some(53));
+        assert_eq!(code(rules, "192.0.2.1", "/private/open/x.gmi"), Some(53));
+        assert_eq!(code(rules, "198.51.100.1", "/private/x.gmi"), Some(51));
+        assert_eq!(code(rules, "198.51.100.1", "/private"), Some(51));
+        assert_eq!(code(rules, "10.1.2.3", "/private/x.gmi"), None);
+        assert_eq!(code(rules, "198.51.100.1", "/private/open/x.gmi"), None);
+        assert_eq!(code(rules, "198.51.100.1", "/privateer.gmi"), None);
+
+        let rules = r#"access_path = { "/private/" = { allow = [ "10.0.0.0/8" ] } }"#;
+        assert_eq!(code(rules, "198.51.100.1", "/private/"), Some(51));
+    }
+}
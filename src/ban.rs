@@ -0,0 +1,99 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::status::Status;
+use crate::table::Table;
+
+#[derive(Debug, Default)]
+pub struct Bans {
+    // Clients whose strikes are all stale are dropped once there are too many.
+    strikes: Table<IpAddr, VecDeque<Instant>>,
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Bans {
+    // Checked at accept time so banned clients never get a TLS handshake.
+    pub fn banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut banned = self.banned.lock().unwrap();
+        match banned.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                banned.remove(&ip);
+                log::info!("Ban on {} expired", ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn strike(&self, ip: IpAddr, rule: &config::Ban) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let window = Duration::from_secs(rule.window);
+        let recent = |_: &_, s: &mut VecDeque<Instant>| {
+            s.back().is_some_and(|t| now.duration_since(*t) < window)
+        };
+        let out = self.strikes.with(recent, |table| {
+            let strikes = table.entry(ip).or_default();
+            while let Some(t) = strikes.front() {
+                if now.duration_since(*t) < window {
+                    break;
+                }
+                strikes.pop_front();
+            }
+            strikes.push_back(now);
+            let out = strikes.len() >= rule.limit as usize;
+            if out {
+                table.remove(&ip);
+            }
+            out
+        });
+
+        if out {
+            self.banned
+                .lock()
+                .unwrap()
+                .insert(ip, now + Duration::from_secs(rule.duration));
+            log::warn!(
+                "Banned {} for {}s after {} bad requests in {}s",
+                ip,
+                rule.duration,
+                rule.limit,
+                rule.window
+            );
+        }
+    }
+
+    // Log the clients that are currently banned and how long they have left.
+    pub fn list(&self) {
+        let now = Instant::now();
+        let mut banned = self.banned.lock().unwrap();
+        banned.retain(|_, until| *until > now);
+        log::info!("{} clients banned", banned.len());
+        for (ip, until) in banned.iter() {
+            log::info!("Banned {} for another {}s", ip, (*until - now).as_secs());
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut banned = self.banned.lock().unwrap();
+        log::info!("Cleared {} bans", banned.len());
+        banned.clear();
+        self.strikes.clear();
+    }
+}
+
+// Bad requests, refused requests and not found count towards a ban.
+pub fn record(srv: &config::ServerCfg, addr: SocketAddr, stat: Status) {
+    let rule = match &srv.ban {
+        Some(r) => r,
+        None => return,
+    };
+    if let Status::BadRequest | Status::ProxyRequestRefused | Status::NotFound = stat {
+        srv.bans.strike(addr.ip(), rule);
+    }
+}
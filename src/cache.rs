@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+
+// Identifies a version of a file. Writing to it changes the mtime, replacing
+// it changes the inode and chmod changes the ctime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl From<&Metadata> for Stamp {
+    fn from(m: &Metadata) -> Self {
+        Stamp {
+            ino: m.ino(),
+            size: m.size(),
+            mtime: (m.mtime(), m.mtime_nsec()),
+            ctime: (m.ctime(), m.ctime_nsec()),
+        }
+    }
+}
+
+// The request path is kept as an OsString so "dir" and "dir/" stay apart. The
+// file that served it, which is the index for directories, is part of the key
+// so a request that resolves to another file doesn't get the old one.
+type Key = (String, OsString, PathBuf);
+
+#[derive(Debug)]
+struct Entry {
+    stamp: Stamp,
+    meta: String,
+    body: Arc<[u8]>,
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: HashMap<Key, Entry>,
+    // Least recently used first.
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Table {
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(e) = self.entries.get_mut(key) {
+            self.order.remove(&e.used);
+            e.used = tick;
+            self.order.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(e) = self.entries.remove(key) {
+            self.order.remove(&e.used);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FileCache {
+    table: Mutex<Table>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl FileCache {
+    async fn get(&self, key: Key) -> Option<(String, Arc<[u8]>)> {
+        let cached = {
+            let table = self.table.lock().unwrap();
+            table
+                .entries
+                .get(&key)
+                .map(|e| (e.stamp, e.meta.clone(), e.body.clone()))
+        };
+        let (stamp, meta, body) = match cached {
+            Some(c) => c,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        let current = tokio::fs::metadata(&key.2)
+            .await
+            .ok()
+            .map(|m| Stamp::from(&m));
+        let mut table = self.table.lock().unwrap();
+        if current != Some(stamp) {
+            table.remove(&key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        table.touch(&key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some((meta, body))
+    }
+
+    fn insert(&self, key: Key, entry: Entry, max: usize) {
+        let mut table = self.table.lock().unwrap();
+        table.remove(&key);
+        while table.entries.len() >= max {
+            let oldest = match table.order.keys().next() {
+                Some(t) => *t,
+                None => break,
+            };
+            if let Some(k) = table.order.remove(&oldest) {
+                table.entries.remove(&k);
+            }
+        }
+        table.entries.insert(key.clone(), entry);
+        table.touch(&key);
+    }
+
+    // Log how well the cache is doing.
+    pub fn stats(&self) {
+        let table = self.table.lock().unwrap();
+        let bytes: usize = table.entries.values().map(|e| e.body.len()).sum();
+        log::info!(
+            "File cache: {} hits, {} misses, {} files, {} bytes",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            table.entries.len(),
+            bytes
+        );
+    }
+
+    // Drop every entry, for when the config that produced their meta changes.
+    pub fn clear(&self) {
+        let mut table = self.table.lock().unwrap();
+        table.entries.clear();
+        table.order.clear();
+    }
+}
+
+fn key(srv: &config::ServerCfg, path: &Path, file: &Path) -> Key {
+    (
+        srv.server.hostname.clone(),
+        path.as_os_str().to_owned(),
+        file.to_path_buf(),
+    )
+}
+
+// Returns the meta and body cached for a request for path served from file if
+// the file hasn't changed on disk since.
+pub async fn get(srv: &config::ServerCfg, path: &Path, file: &Path) -> Option<(String, Arc<[u8]>)> {
+    srv.cache.as_ref()?;
+    srv.files.get(key(srv, path, file)).await
+}
+
+// Whether a file of this size should be read into the cache.
+pub fn wanted(srv: &config::ServerCfg, m: &Metadata) -> bool {
+    match &srv.cache {
+        Some(rule) => rule.entries > 0 && m.len() <= rule.max_size,
+        None => false,
+    }
+}
+
+// Cache a file served for path. m is the metadata the file had before it was
+// read so a change while reading shows up as stale on the next request.
+pub fn insert(
+    srv: &config::ServerCfg,
+    path: &Path,
+    file: &Path,
+    m: &Metadata,
+    meta: &str,
+    body: Arc<[u8]>,
+) {
+    let max = match &srv.cache {
+        Some(rule) => rule.entries,
+        None => return,
+    };
+    let entry = Entry {
+        stamp: Stamp::from(m),
+        meta: meta.to_string(),
+        body,
+        used: 0,
+    };
+    srv.files.insert(key(srv, path, file), entry, max);
+}
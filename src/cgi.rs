@@ -68,13 +68,16 @@ fn envs(
         }
     }
 
-    match &srv.server.cgienv {
-        Some(c) => {
-            for (k, v) in c.iter() {
-                envs.insert(k.clone(), v.clone());
-            }
+    // Scripts run from the disk so no git root serves them. The first one's
+    // commit stands for the vhost.
+    if let Some((_, commit)) = srv.server.vfs.commits().next() {
+        envs.insert("GIT_COMMIT".to_string(), commit.to_string());
+    }
+
+    if let Some(c) = &srv.server.cgienv {
+        for (k, v) in c.iter() {
+            envs.insert(k.clone(), v.clone());
         }
-        None => {}
     }
     envs
 }
@@ -114,9 +117,11 @@ pub async fn cgi(
         std::env::set_current_dir(p)?;
     }
 
+    // Killed if the script times out or the server shuts down.
     let cmd = Command::new(path.to_str().unwrap())
         .env_clear()
         .envs(&envs)
+        .kill_on_drop(true)
         .output();
 
     let cmd = match tokio::time::timeout(tokio::time::Duration::from_secs(5), cmd).await {
@@ -152,7 +157,7 @@ pub async fn cgi(
 }
 
 #[cfg(feature = "scgi")]
-pub async fn scgi(addr: String, u: url::Url, mut con: conn::Connection) -> Result<(), io::Error> {
+pub async fn scgi(addr: &str, u: url::Url, mut con: conn::Connection) -> Result<(), io::Error> {
     let addr = addr
         .to_socket_addrs()?
         .next()
@@ -184,11 +189,12 @@ pub async fn scgi(addr: String, u: url::Url, mut con: conn::Connection) -> Resul
     stream.flush().await?;
 
     let mut buf = vec![];
-    if let Err(_) = tokio::time::timeout(
+    if tokio::time::timeout(
         tokio::time::Duration::from_secs(5),
         stream.read_to_end(&mut buf),
     )
     .await
+    .is_err()
     {
         logger::logger(con.peer_addr, Status::CGIError, u.as_str());
         con.send_status(Status::CGIError, None).await?;
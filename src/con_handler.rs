@@ -1,23 +1,40 @@
+#[cfg(feature = "cgi")]
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use tokio::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use tokio::io::{self, AsyncBufReadExt, AsyncWrite, BufReader};
+#[cfg(feature = "cgi")]
 use url::Url;
 
+use crate::access;
+use crate::cache;
 #[cfg(any(feature = "cgi", feature = "scgi"))]
 use crate::cgi;
+use crate::config;
 use crate::conn;
+use crate::dirlist;
+use crate::feed;
+use crate::include;
 use crate::logger;
+#[cfg(feature = "markdown")]
+use crate::markdown;
+use crate::overrides;
+use crate::ratelimit;
 #[cfg(feature = "proxy")]
 use crate::revproxy;
 use crate::status::Status;
+use crate::template;
 use crate::util;
+use crate::vfs;
 
 type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-fn get_mime(path: &Path) -> String {
+// Index files tried when the server doesn't set any.
+const INDEX: [&str; 2] = ["index.gemini", "index.gmi"];
+
+fn get_mime(path: &Path, dir: bool) -> String {
     let mut mime = "text/gemini".to_string();
-    if path.is_dir() {
+    if dir {
         return mime;
     }
     let ext = match path.extension() {
@@ -33,21 +50,94 @@ fn get_mime(path: &Path) -> String {
     mime
 }
 
-async fn get_binary(mut con: conn::Connection, path: PathBuf, meta: String) -> io::Result<()> {
-    let fd = File::open(path).await?;
+// Guess a text file's charset from its first bytes. Anything that isn't
+// UTF-8 is sent as Latin-1 since every byte sequence is valid in it.
+fn detect_charset(buf: &[u8]) -> &'static str {
+    if buf.starts_with(&[0xfe, 0xff]) {
+        return "utf-16be";
+    }
+    if buf.starts_with(&[0xff, 0xfe]) {
+        return "utf-16le";
+    }
+    match std::str::from_utf8(buf) {
+        Ok(_) => "utf-8",
+        // The buffer may end part way through a character.
+        Err(e) if e.error_len().is_none() => "utf-8",
+        Err(_) => "iso-8859-1",
+    }
+}
+
+// Pages with includes are sent in chunks of about this size so lines don't
+// each need a write of their own.
+const CHUNK: usize = 64 * 1024;
+
+async fn send_file(
+    mut con: conn::Connection,
+    fd: vfs::Reader,
+    mut meta: String,
+    detect: bool,
+    mut wrap: Option<(String, String)>,
+    includes: Option<include::Expander<'_>>,
+) -> io::Result<()> {
     let mut reader = BufReader::with_capacity(1024 * 1024, fd);
+    if detect {
+        let charset = detect_charset(reader.fill_buf().await?);
+        meta += &format!("; charset={}", charset);
+        if !template::fits(charset) {
+            wrap = None;
+        }
+    }
     con.send_raw(format!("{} {}\r\n", Status::Success as u8, &meta).as_bytes())
         .await?;
-    loop {
-        let len = {
-            let buf = reader.fill_buf().await?;
-            con.send_raw(buf).await?;
-            buf.len()
-        };
-        if len == 0 {
-            break;
+    if let Some((header, _)) = &wrap {
+        con.send_raw(header.as_bytes()).await?;
+    }
+    let mut last = b'\n';
+    if let Some(includes) = includes {
+        // Read a line at a time so directives can be swapped for the files
+        // they include.
+        let mut out = Vec::with_capacity(CHUNK);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                break;
+            }
+            match includes.line(&line).await {
+                Some(included) => out.extend_from_slice(&included),
+                None => out.extend_from_slice(&line),
+            }
+            if out.len() >= CHUNK {
+                con.send_raw(&out).await?;
+                last = out[out.len() - 1];
+                out.clear();
+            }
+        }
+        if let Some(b) = out.last() {
+            con.send_raw(&out).await?;
+            last = *b;
+        }
+    } else {
+        loop {
+            let len = {
+                let buf = reader.fill_buf().await?;
+                con.send_raw(buf).await?;
+                if let Some(b) = buf.last() {
+                    last = *b;
+                }
+                buf.len()
+            };
+            if len == 0 {
+                break;
+            }
+            reader.consume(len);
         }
-        reader.consume(len);
+    }
+    if let Some((_, footer)) = &wrap {
+        if !footer.is_empty() && last != b'\n' {
+            con.send_raw(b"\r\n").await?;
+        }
+        con.send_raw(footer.as_bytes()).await?;
     }
 
     futures_util::future::poll_fn(|ctx| std::pin::Pin::new(&mut con.stream).poll_shutdown(ctx))
@@ -55,49 +145,10 @@ async fn get_binary(mut con: conn::Connection, path: PathBuf, meta: String) -> i
     Ok(())
 }
 
-async fn gen_dir_list(path: PathBuf, u: &url::Url) -> Result<String> {
-    let mut dirs: Vec<String> = Vec::new();
-    let mut files: Vec<String> = Vec::new();
-
-    // needs work
-    let mut dir = fs::read_dir(&path).await?;
-    while let Some(file) = dir.next_entry().await? {
-        let m = file.metadata().await?;
-        let perm = m.permissions();
-        if perm.mode() & 0o0444 != 0o0444 {
-            continue;
-        }
-        let file = file.path();
-        let p = file.strip_prefix(&path).unwrap();
-        let ps = match p.to_str() {
-            Some(s) => s,
-            None => continue,
-        };
-        let ep = match u.join(ps) {
-            Ok(p) => p,
-            _ => continue,
-        };
-        if m.is_dir() {
-            dirs.push(format!("=> {}/ {}/\r\n", ep, p.display()));
-        } else {
-            files.push(format!("=> {} {}\r\n", ep, p.display()));
-        }
-    }
-
-    dirs.sort();
-    files.sort();
-
-    let mut list = String::from("# Directory Listing\r\n\r\n");
-    list.push_str(&format!("Path: {}\r\n\r\n", u.path()));
-
-    for dir in dirs {
-        list.push_str(&dir);
-    }
-    for file in files {
-        list.push_str(&file);
-    }
-
-    Ok(list)
+async fn send_cached(mut con: conn::Connection, meta: &str, body: &[u8]) -> io::Result<()> {
+    con.send_raw(format!("{} {}\r\n", Status::Success as u8, meta).as_bytes())
+        .await?;
+    con.send_stream(&mut &body[..]).await
 }
 
 // Handle CGI and return Ok(true), or indicate this request wasn't for CGI with Ok(false)
@@ -106,10 +157,10 @@ async fn handle_cgi(
     con: &mut conn::Connection,
     request: &str,
     url: &Url,
-    full_path: &PathBuf,
+    full_path: &Path,
 ) -> Result<bool> {
     if con.srv.server.cgi.unwrap_or(false) {
-        let mut path = full_path.clone();
+        let mut path = full_path.to_path_buf();
         let mut segments = url.path_segments().unwrap();
         let mut path_info = "".to_string();
 
@@ -151,76 +202,141 @@ async fn handle_cgi(
     Ok(false)
 }
 
+// Whether a file is kept from being sent even though it exists: it has to be
+// readable by everyone, executables are only ever run as CGI scripts and
+// override files are never sent. Includes follow the same rules.
+#[cfg_attr(not(feature = "cgi"), allow(unused_variables))]
+pub fn hidden(srv: &config::Server, path: &Path, meta: &vfs::Stat) -> bool {
+    if meta.mode & 0o0444 != 0o0444
+        || (meta.is_file() && meta.mode & 0o0111 == 0o0111)
+        || overrides::is_file(path)
+    {
+        return true;
+    }
+    #[cfg(feature = "cgi")]
+    if let (Some(true), Some(c)) = (srv.cgi, &srv.cgipath) {
+        return path.starts_with(c);
+    }
+    false
+}
+
+// The first file named path plus one of the server's extensions, so /about can
+// serve about.gmi.
+fn try_extensions(srv: &config::Server, path: &Path) -> Option<PathBuf> {
+    srv.try_extensions.as_ref()?.iter().find_map(|ext| {
+        let mut p = path.as_os_str().to_owned();
+        p.push(ext);
+        let p = PathBuf::from(p);
+        if srv.vfs.is_file(&p) {
+            Some(p)
+        } else {
+            None
+        }
+    })
+}
+
+// Whether a document root layer has something to serve at path.
+fn found(srv: &config::Server, path: &Path) -> bool {
+    if srv.vfs.exists(path) || try_extensions(srv, path).is_some() {
+        return true;
+    }
+    #[cfg(feature = "markdown")]
+    if markdown::raw(srv, &mut path.to_path_buf()) {
+        return true;
+    }
+    false
+}
+
+// The longest alias whose prefix covers whole segments of the URL path along
+// with the directory it's served from.
+fn alias<'a>(srv: &'a config::Server, path: &str) -> Option<(&'a str, &'a str)> {
+    srv.alias
+        .as_ref()?
+        .iter()
+        .map(|(p, d)| (p.trim_end_matches('/'), d.as_str()))
+        .filter(|(p, _)| {
+            path.strip_prefix(p)
+                .is_some_and(|r| r.is_empty() || r.starts_with('/'))
+        })
+        .max_by_key(|(p, _)| p.len())
+}
+
 // TODO Rewrite this monster.
 pub async fn handle_connection(mut con: conn::Connection, url: url::Url) -> Result {
-    let index = match &con.srv.server.index {
-        Some(i) => i.clone(),
-        None => "index.gemini".to_string(),
-    };
+    // Shares the vhost config so it can be borrowed while con is in use.
+    let srv = con.srv.clone();
+    if let Some(stat) = access::check(&con, &url) {
+        logger::logger(con.peer_addr, stat, url.as_str());
+        con.send_status(stat, None).await?;
+        return Ok(());
+    }
 
-    match &con.srv.server.redirect.to_owned() {
-        Some(re) => {
-            let u = match url.path() {
-                "/" => "/",
-                _ => url.path().trim_end_matches('/'),
-            };
-            if let Some(r) = re.get(u) {
-                logger::logger(con.peer_addr, Status::RedirectTemporary, url.as_str());
-                con.send_status(Status::RedirectTemporary, Some(r)).await?;
-                return Ok(());
-            }
+    if let Some(wait) = ratelimit::check(&con, &url) {
+        logger::logger(con.peer_addr, Status::SlowDown, url.as_str());
+        con.send_status(Status::SlowDown, Some(&wait.to_string()))
+            .await?;
+        return Ok(());
+    }
+
+    if let Some(re) = &srv.server.redirect {
+        let u = match url.path() {
+            "/" => "/",
+            _ => url.path().trim_end_matches('/'),
+        };
+        if let Some(r) = re.get(u) {
+            logger::logger(con.peer_addr, Status::RedirectTemporary, url.as_str());
+            con.send_status(Status::RedirectTemporary, Some(r)).await?;
+            return Ok(());
         }
-        None => {}
     }
 
     #[cfg(feature = "proxy")]
-    if let Some(pr) = con.srv.server.proxy_all.to_owned() {
-        let host_port: Vec<&str> = pr.splitn(2, ':').collect();
+    if let Some(pr) = &srv.server.proxy_all {
+        let host_port: Vec<&str> = pr.addr.splitn(2, ':').collect();
         let host = host_port[0];
-        let port: Option<u16>;
-        if host_port.len() == 2 {
-            port = host_port[1].parse().ok();
+        let port: Option<u16> = if host_port.len() == 2 {
+            host_port[1].parse().ok()
         } else {
-            port = None;
-        }
+            None
+        };
 
         let mut upstream_url = url.clone();
         upstream_url.set_host(Some(host)).unwrap();
         upstream_url.set_port(port).unwrap();
 
-        revproxy::proxy_all(pr.as_str(), upstream_url, con).await?;
+        revproxy::proxy_all(pr, upstream_url, con).await?;
         return Ok(());
     }
 
     #[cfg(feature = "proxy")]
-    match &con.srv.server.proxy {
-        Some(pr) => {
-            if let Some(s) = url.path_segments().map(|c| c.collect::<Vec<_>>()) {
-                if let Some(p) = pr.get(s[0]) {
-                    revproxy::proxy(p.to_string(), url, con).await?;
-                    return Ok(());
-                }
+    if let Some(pr) = &srv.server.proxy {
+        if let Some(s) = url.path_segments().map(|c| c.collect::<Vec<_>>()) {
+            if let Some(p) = pr.get(s[0]) {
+                revproxy::proxy(p, url, con).await?;
+                return Ok(());
             }
         }
-        None => {}
     }
 
     #[cfg(feature = "scgi")]
-    match &con.srv.server.scgi {
-        Some(sc) => {
-            let u = match url.path() {
-                "/" => "/",
-                _ => url.path().trim_end_matches('/'),
-            };
-            if let Some(r) = sc.get(u) {
-                cgi::scgi(r.to_string(), url, con).await?;
-                return Ok(());
-            }
+    if let Some(sc) = &srv.server.scgi {
+        let u = match url.path() {
+            "/" => "/",
+            _ => url.path().trim_end_matches('/'),
+        };
+        if let Some(r) = sc.get(u) {
+            cgi::scgi(r, url, con).await?;
+            return Ok(());
         }
-        None => {}
     }
 
     let mut path = PathBuf::new();
+    // The document root the request is served from.
+    let mut root = PathBuf::new();
+    // The URL path the document root is served under.
+    let mut base = String::from("/");
+    // Every layer of the document root, in order.
+    let mut roots = Vec::new();
 
     if url.path().starts_with("/~") && con.srv.server.usrdir.unwrap_or(false) {
         let usr = url.path().trim_start_matches("/~");
@@ -230,6 +346,8 @@ pub async fn handle_connection(mut con: conn::Connection, url: url::Url) -> Resu
         } else {
             path.push("/home/");
         }
+        root = path.join(usr[0]).join("public_gemini");
+        base = format!("/~{}/", usr[0]);
         if usr.len() == 2 {
             path.push(format!(
                 "{}/{}/{}",
@@ -240,19 +358,105 @@ pub async fn handle_connection(mut con: conn::Connection, url: url::Url) -> Resu
         } else {
             path.push(format!("{}/{}/", usr[0], "public_gemini"));
         }
+    } else if let Some((prefix, dir)) = alias(&srv.server, url.path()) {
+        path.push(dir);
+        root.push(dir);
+        base = format!("{}/", prefix);
+        let decoded = util::url_decode(&url.path().as_bytes()[prefix.len()..]);
+        path.push(decoded.trim_start_matches('/'));
     } else {
-        path.push(&con.srv.server.dir);
-        if url.path() != "" || url.path() != "/" {
-            let decoded = util::url_decode(url.path().as_bytes()).trim_start_matches('/').to_owned();
-            path.push(decoded);
+        let decoded = util::url_decode(url.path().as_bytes())
+            .trim_start_matches('/')
+            .to_owned();
+        roots = srv.server.dir.0.iter().map(PathBuf::from).collect();
+        // The first layer with something at the path serves it.
+        root = match roots.iter().find(|r| found(&srv.server, &r.join(&decoded))) {
+            Some(r) => r.clone(),
+            None => roots[0].clone(),
+        };
+        path = root.join(decoded);
+    }
+    if roots.is_empty() {
+        roots.push(root.clone());
+    }
+
+    // An encoded "../" gets through URL parsing so check what it decoded to.
+    match path.strip_prefix(&root) {
+        Ok(rel) if !rel.components().any(|c| c == Component::ParentDir) => {}
+        _ => {
+            logger::logger(con.peer_addr, Status::NotFound, url.as_str());
+            con.send_status(Status::NotFound, None).await?;
+            return Ok(());
+        }
+    }
+
+    // Find the file first so overrides apply to it under every URL it has.
+    let vfs = &srv.server.vfs;
+    let requested = path.clone();
+    #[cfg(feature = "markdown")]
+    let raw = markdown::raw(&srv.server, &mut path);
+
+    if !vfs.exists(&path) && !url.path().ends_with('/') {
+        if let Some(p) = try_extensions(&srv.server, &path) {
+            path = p;
         }
     }
 
-    if !path.exists() {
+    let rel = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+    let mut over = srv
+        .overrides
+        .get(vfs, &roots, &rel, vfs.is_dir(&path))
+        .await;
+    if let Some(stat) = over.check(con.peer_addr.ip(), srv.server.deny_status) {
+        logger::logger(con.peer_addr, stat, url.as_str());
+        con.send_status(stat, None).await?;
+        return Ok(());
+    }
+    if over.gone() {
+        logger::logger(con.peer_addr, Status::Gone, url.as_str());
+        con.send_status(Status::Gone, None).await?;
+        return Ok(());
+    }
+    if let Some(to) = over.redirect(&url) {
+        logger::logger(con.peer_addr, Status::RedirectTemporary, url.as_str());
+        con.send_status(Status::RedirectTemporary, Some(to.as_str()))
+            .await?;
+        return Ok(());
+    }
+
+    let generated = match feed::check(&srv, &url).await {
+        Ok(g) => g,
+        Err(stat) => {
+            logger::logger(con.peer_addr, stat, url.as_str());
+            con.send_status(stat, None).await?;
+            return Ok(());
+        }
+    };
+    if let Some((mime, mut body)) = generated {
+        let mut mime = mime.to_string();
+        if mime == "text/gemini" {
+            if let Some(w) = template::wrap(&srv.server, &url, &util::rule_path(&url), None) {
+                body = template::apply_text(body, &w);
+            }
+            if let Some(lang) = over.lang().or(srv.server.lang.as_deref()) {
+                mime += &format!("; lang={}", lang);
+            }
+        }
+        logger::logger(con.peer_addr, Status::Success, url.as_str());
+        con.send_body(Status::Success, Some(&mime), Some(body))
+            .await?;
+        return Ok(());
+    }
+
+    if !vfs.exists(&path) {
         // See if it's a subpath of a CGI script before returning NotFound
         #[cfg(feature = "cgi")]
-        if handle_cgi(&mut con, url.as_str(), &url, &path).await? {
-            return Ok(());
+        let rel = requested.strip_prefix(&root).unwrap_or(&requested);
+        #[cfg(feature = "cgi")]
+        for r in &roots {
+            if handle_cgi(&mut con, url.as_str(), &url, &r.join(rel)).await? {
+                return Ok(());
+            }
         }
 
         logger::logger(con.peer_addr, Status::NotFound, url.as_str());
@@ -260,13 +464,10 @@ pub async fn handle_connection(mut con: conn::Connection, url: url::Url) -> Resu
         return Ok(());
     }
 
-    let mut meta = tokio::fs::metadata(&path).await?;
-    let mut perm = meta.permissions();
+    let mut meta = vfs.stat(&path).await?;
 
-    // TODO fix me
-    // This block is terrible
     if meta.is_dir() {
-        if !url.path().ends_with('/') {
+        if !url.path().ends_with('/') && srv.server.dir_redirect.unwrap_or(true) {
             logger::logger(con.peer_addr, Status::RedirectPermanent, url.as_str());
             con.send_status(
                 Status::RedirectPermanent,
@@ -275,60 +476,185 @@ pub async fn handle_connection(mut con: conn::Connection, url: url::Url) -> Resu
             .await?;
             return Ok(());
         }
-        if path.join(&index).exists() {
-            path.push(index);
-            meta = tokio::fs::metadata(&path).await?;
-            perm = meta.permissions();
-            if perm.mode() & 0o0444 != 0o444 {
-                let mut p = path.clone();
-                p.pop();
-                path.push(format!("{}/", p.display()));
-                meta = tokio::fs::metadata(&path).await?;
-                perm = meta.permissions();
+        // The first readable index in any layer wins, otherwise the directory
+        // is listed.
+        let names = match over.index().or(srv.server.index.as_ref()) {
+            Some(i) => i.0.iter().map(|n| n.as_str()).collect(),
+            None => INDEX.to_vec(),
+        };
+        let mut candidates = Vec::new();
+        for name in names {
+            for r in &roots {
+                candidates.push((r.clone(), r.join(&rel).join(name)));
+            }
+        }
+        for (r, p) in candidates {
+            match vfs.stat(&p).await {
+                Ok(m) if m.is_file() && m.mode & 0o0444 == 0o0444 => {
+                    root = r;
+                    path = p;
+                    meta = m;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        // An index file can be marked as gone on its own.
+        if meta.is_file() {
+            let rel = path.strip_prefix(&root).unwrap_or(&path);
+            over = srv.overrides.get(vfs, &roots, rel, false).await;
+            if over.gone() {
+                logger::logger(con.peer_addr, Status::Gone, url.as_str());
+                con.send_status(Status::Gone, None).await?;
+                return Ok(());
             }
         }
     }
 
-    #[cfg(feature = "cgi")]
-    if handle_cgi(&mut con, url.as_str(), &url, &path).await? {
+    // Extensions and index files give a file more than one URL so the path
+    // rules are checked against the one it's served from as well.
+    let served = match path.strip_prefix(&root) {
+        Ok(rel) => util::clean_path(&format!("{}{}", base, rel.to_string_lossy())),
+        Err(_) => util::rule_path(&url),
+    };
+    if let Some(stat) = access::check_path(&con, &served) {
+        logger::logger(con.peer_addr, stat, url.as_str());
+        con.send_status(stat, None).await?;
         return Ok(());
     }
 
-    if meta.is_file() && perm.mode() & 0o0111 == 0o0111 {
+    // Override files are never served, even as an index file or a script.
+    if overrides::is_file(&path) {
         logger::logger(con.peer_addr, Status::NotFound, url.as_str());
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
 
-    if perm.mode() & 0o0444 != 0o0444 {
+    #[cfg(feature = "cgi")]
+    if handle_cgi(&mut con, url.as_str(), &url, &path).await? {
+        return Ok(());
+    }
+
+    if hidden(&srv.server, &path, &meta) {
         logger::logger(con.peer_addr, Status::NotFound, url.as_str());
         con.send_status(Status::NotFound, None).await?;
         return Ok(());
     }
 
-    let mut mime = get_mime(&path);
+    let mut mime = match over.mime(&path).filter(|_| meta.is_file()) {
+        Some(m) => m.to_string(),
+        None => get_mime(&path, meta.is_dir()),
+    };
+    #[cfg(feature = "markdown")]
+    let convert = !raw && meta.is_file() && markdown::convert(&srv.server, &path);
+    #[cfg(feature = "markdown")]
+    if convert {
+        mime = "text/gemini".to_string();
+    }
+    let includes = mime == "text/gemini" && include::enabled(&srv.server);
+    let mut wrap = if mime == "text/gemini" {
+        template::wrap(&srv.server, &url, &served, meta.mtime)
+    } else {
+        None
+    };
     if meta.is_file() {
-        if mime == "text/gemini" && con.srv.server.lang.is_some() {
-            mime += &("; lang=".to_string() + &con.srv.server.lang.to_owned().unwrap());
+        if mime == "text/gemini" {
+            if let Some(lang) = over.lang().or(srv.server.lang.as_deref()) {
+                mime += &format!("; lang={}", lang);
+            }
         }
-        if !mime.starts_with("text/") {
+        #[cfg(feature = "markdown")]
+        if convert {
+            let mut page = srv.pages.get(vfs, &path, &meta).await?.as_bytes().to_vec();
+            // Includes are expanded in the converted page so they're gemtext
+            // rather than markdown.
+            if includes {
+                page = include::expand(&srv.server, &root, &path, page).await.0;
+            }
+            let body = match &wrap {
+                Some(w) => template::apply(&page, w),
+                None => page,
+            };
             logger::logger(con.peer_addr, Status::Success, url.as_str());
-            get_binary(con, path, mime).await?;
+            send_cached(con, &mime, &body).await?;
             return Ok(());
         }
-        match fs::read_to_string(path).await {
-            Ok(c) => {
-                con.send_body(Status::Success, Some(&mime), Some(c)).await?;
+        let mut detect = false;
+        if mime.starts_with("text/") {
+            match srv.server.charset.as_deref() {
+                Some("detect") => detect = true,
+                Some(c) => {
+                    mime += &format!("; charset={}", c);
+                    if !template::fits(c) {
+                        wrap = None;
+                    }
+                }
+                None => {}
+            }
+        }
+        // Only files on the local disk are cached, archives are already in
+        // memory. Cached responses don't know which overrides they were sent
+        // with.
+        let cache = meta
+            .local
+            .as_ref()
+            .filter(|m| over.is_empty() && cache::wanted(&srv, m));
+        if cache.is_some() {
+            if let Some((meta, body)) = cache::get(&srv, &requested, &path).await {
                 logger::logger(con.peer_addr, Status::Success, url.as_str());
+                send_cached(con, &meta, &body).await?;
+                return Ok(());
             }
-            Err(e) => {
-                println!("{}", e);
-                con.send_status(Status::NotFound, None).await?;
-                logger::logger(con.peer_addr, Status::NotFound, url.as_str());
+        }
+        if cache.is_some() {
+            let body = vfs.read(&path).await?;
+            // Pages with includes aren't cached as the cache only notices
+            // changes to the page itself.
+            let (mut body, included) = match includes {
+                true => include::expand(&srv.server, &root, &path, body).await,
+                false => (body, false),
+            };
+            if detect {
+                let charset = detect_charset(&body);
+                mime += &format!("; charset={}", charset);
+                if !template::fits(charset) {
+                    wrap = None;
+                }
+            }
+            if let Some(w) = &wrap {
+                body = template::apply(&body, w);
             }
+            let body: Arc<[u8]> = body.into();
+            if let (false, Some(m)) = (included, cache) {
+                cache::insert(&srv, &requested, &path, m, &mime, body.clone());
+            }
+            logger::logger(con.peer_addr, Status::Success, url.as_str());
+            send_cached(con, &mime, &body).await?;
+            return Ok(());
         }
+        let includes = match includes {
+            true => include::expander(&srv.server, &root, &path).await,
+            false => None,
+        };
+        logger::logger(con.peer_addr, Status::Success, url.as_str());
+        send_file(con, vfs.open(&path).await?, mime, detect, wrap, includes).await?;
     } else {
-        let dir = gen_dir_list(path, &url).await?;
+        let layers: Vec<PathBuf> = roots
+            .iter()
+            .map(|r| r.join(&rel))
+            .filter(|d| vfs.is_dir(d))
+            .collect();
+        let dir = match dirlist::list(&srv, &layers, &url, over.dirlist()).await? {
+            Some(d) => match &wrap {
+                Some(w) => template::apply_text(d, w),
+                None => d,
+            },
+            None => {
+                logger::logger(con.peer_addr, Status::NotFound, url.as_str());
+                con.send_status(Status::NotFound, None).await?;
+                return Ok(());
+            }
+        };
         con.send_body(Status::Success, Some(&mime), Some(dir))
             .await?;
         logger::logger(con.peer_addr, Status::Success, url.as_str());
@@ -1,11 +1,24 @@
 extern crate serde_derive;
 extern crate toml;
+use crate::access;
+use crate::ban;
+use crate::cache;
+use crate::dirlist;
+use crate::feed;
 use crate::lib::errors;
+#[cfg(feature = "proxy")]
+use crate::lib::proxy_protocol;
+#[cfg(feature = "markdown")]
+use crate::markdown;
+use crate::overrides;
+use crate::ratelimit;
+use crate::vfs;
 use std::collections::HashMap;
 use std::env;
 use std::net;
 use std::net::ToSocketAddrs;
 use std::path;
+use std::sync::Arc;
 use tokio::fs;
 use tokio::io;
 
@@ -15,19 +28,102 @@ type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error + Send +
 pub struct Config {
     pub port: Option<u16>,
     pub host: Option<String>,
-    pub interface: Option<Vec<net::SocketAddr>>,
+    pub interface: Option<Vec<Interface>>,
     pub log: Option<String>,
+    pub ratelimit: Option<RateLimit>,
+    pub ban: Option<Ban>,
+    pub drain_timeout: Option<u64>,
+    pub git_poll: Option<u64>,
+    pub cache: Option<Cache>,
     pub server: Vec<Server>,
 }
 
+// An interface is either just an address or a table with its options.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(from = "InterfaceEntry")]
+pub struct Interface {
+    pub addr: net::SocketAddr,
+    pub proxy_protocol: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InterfaceEntry {
+    Addr(net::SocketAddr),
+    Table {
+        addr: net::SocketAddr,
+        proxy_protocol: Option<bool>,
+    },
+}
+
+impl From<InterfaceEntry> for Interface {
+    fn from(i: InterfaceEntry) -> Self {
+        match i {
+            InterfaceEntry::Addr(addr) => Interface {
+                addr,
+                proxy_protocol: false,
+            },
+            InterfaceEntry::Table {
+                addr,
+                proxy_protocol,
+            } => Interface {
+                addr,
+                proxy_protocol: proxy_protocol.unwrap_or(false),
+            },
+        }
+    }
+}
+
+// A proxy target is either just host:port or a table with its options.
+#[cfg(feature = "proxy")]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "ProxyEntry")]
+pub struct Proxy {
+    pub addr: String,
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+#[cfg(feature = "proxy")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxyEntry {
+    Addr(String),
+    Table {
+        addr: String,
+        proxy_protocol: Option<proxy_protocol::Version>,
+    },
+}
+
+#[cfg(feature = "proxy")]
+impl From<ProxyEntry> for Proxy {
+    fn from(p: ProxyEntry) -> Self {
+        match p {
+            ProxyEntry::Addr(addr) => Proxy {
+                addr,
+                proxy_protocol: None,
+            },
+            ProxyEntry::Table {
+                addr,
+                proxy_protocol,
+            } => Proxy {
+                addr,
+                proxy_protocol,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub hostname: String,
-    pub dir: String,
+    pub dir: Dir,
     pub key: String,
     pub cert: String,
-    pub index: Option<String>,
+    pub index: Option<Index>,
+    pub try_extensions: Option<Vec<String>>,
+    pub dir_redirect: Option<bool>,
     pub lang: Option<String>,
+    pub charset: Option<String>,
     #[cfg(feature = "cgi")]
     pub cgi: Option<bool>,
     #[cfg(feature = "cgi")]
@@ -35,19 +131,355 @@ pub struct Server {
     #[cfg(any(feature = "cgi", feature = "scgi"))]
     pub cgienv: Option<HashMap<String, String>>,
     pub usrdir: Option<bool>,
+    pub alias: Option<HashMap<String, String>>,
     #[cfg(feature = "proxy")]
-    pub proxy: Option<HashMap<String, String>>,
+    pub proxy: Option<HashMap<String, Proxy>>,
     #[cfg(feature = "proxy")]
-    pub proxy_all: Option<String>,
+    pub proxy_all: Option<Proxy>,
     pub redirect: Option<HashMap<String, String>>,
     #[cfg(feature = "scgi")]
     pub scgi: Option<HashMap<String, String>>,
+    pub ratelimit_path: Option<HashMap<String, RateLimit>>,
+    pub allow: Option<Vec<access::Cidr>>,
+    pub deny: Option<Vec<access::Cidr>>,
+    pub deny_status: Option<u8>,
+    pub access_path: Option<HashMap<String, Access>>,
+    pub dirlist: Option<DirList>,
+    pub dirlist_path: Option<HashMap<String, DirList>>,
+    pub feed: Option<Feed>,
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub template_path: Option<HashMap<String, Template>>,
+    pub include: Option<Include>,
+    #[cfg(feature = "markdown")]
+    pub markdown: Option<Markdown>,
+    // Contents of the header and footer files, read when the config loads.
+    #[serde(skip)]
+    pub fragments: HashMap<String, String>,
+    // Where the files under dir are read from, set up when the config loads.
+    #[serde(skip)]
+    pub vfs: vfs::Vfs,
+}
+
+// Settings that take either one name or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Names {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Names> for Vec<String> {
+    fn from(n: Names) -> Self {
+        match n {
+            Names::One(name) => vec![name],
+            Names::Many(names) => names,
+        }
+    }
+}
+
+// The document root is either one directory or a list of them layered in
+// order. A path is served from the first one that has it.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "Names")]
+pub struct Dir(pub Vec<String>);
+
+impl From<Names> for Dir {
+    fn from(n: Names) -> Self {
+        Dir(n.into())
+    }
+}
+
+// The index is either one filename or a list of them tried in order.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "Names")]
+pub struct Index(pub Vec<String>);
+
+impl From<Names> for Index {
+    fn from(n: Names) -> Self {
+        Index(n.into())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    // Requests per second a client is allowed on average.
+    pub rate: f64,
+    // How many requests a client can make in a row before being slowed down.
+    pub burst: u32,
+    // Key clients by certificate fingerprint when they present one.
+    pub cert: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Ban {
+    // How many bad requests a client can make within window seconds.
+    pub limit: u32,
+    pub window: u64,
+    // How many seconds a client stays banned.
+    pub duration: u64,
+}
+
+// Directory listings are either turned on or off or configured with a table.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "DirListEntry")]
+pub struct DirList {
+    pub enabled: bool,
+    // List files starting with a dot.
+    pub dotfiles: bool,
+    // Names matching any of these patterns are left out.
+    pub exclude: Vec<String>,
+    pub sort: dirlist::Sort,
+    // Show file sizes and modification dates next to the names.
+    pub size: bool,
+    pub date: bool,
+    // Label gemtext files with their first heading instead of their name.
+    pub titles: bool,
+}
+
+impl Default for DirList {
+    fn default() -> Self {
+        DirList {
+            enabled: true,
+            dotfiles: false,
+            exclude: Vec::new(),
+            sort: dirlist::Sort::default(),
+            size: false,
+            date: false,
+            titles: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DirListEntry {
+    Enabled(bool),
+    Table {
+        enabled: Option<bool>,
+        dotfiles: Option<bool>,
+        exclude: Option<Vec<String>>,
+        sort: Option<dirlist::Sort>,
+        size: Option<bool>,
+        date: Option<bool>,
+        titles: Option<bool>,
+    },
+}
+
+impl From<DirListEntry> for DirList {
+    fn from(d: DirListEntry) -> Self {
+        match d {
+            DirListEntry::Enabled(enabled) => DirList {
+                enabled,
+                ..Default::default()
+            },
+            DirListEntry::Table {
+                enabled,
+                dotfiles,
+                exclude,
+                sort,
+                size,
+                date,
+                titles,
+            } => DirList {
+                enabled: enabled.unwrap_or(true),
+                dotfiles: dotfiles.unwrap_or(false),
+                exclude: exclude.unwrap_or_default(),
+                sort: sort.unwrap_or_default(),
+                size: size.unwrap_or(false),
+                date: date.unwrap_or(false),
+                titles: titles.unwrap_or(false),
+            },
+        }
+    }
+}
+
+// A gemlog is either just its path or a table with its options.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "FeedEntry")]
+pub struct Feed {
+    // Always starts and ends with a slash.
+    pub path: String,
+    pub atom: String,
+    pub title: Option<String>,
+    // The feed's author in the Atom feed. Defaults to the hostname.
+    pub author: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FeedEntry {
+    Path(String),
+    Table {
+        path: String,
+        atom: Option<String>,
+        title: Option<String>,
+        author: Option<String>,
+    },
+}
+
+impl From<FeedEntry> for Feed {
+    fn from(f: FeedEntry) -> Self {
+        let (path, atom, title, author) = match f {
+            FeedEntry::Path(path) => (path, None, None, None),
+            FeedEntry::Table {
+                path,
+                atom,
+                title,
+                author,
+            } => (path, atom, title, author),
+        };
+        let path = format!("/{}/", path.trim_matches('/')).replace("//", "/");
+        // A relative atom path is inside the gemlog.
+        let atom = match atom {
+            Some(a) if a.starts_with('/') => a,
+            Some(a) => format!("{}{}", path, a),
+            None => format!("{}atom.xml", path),
+        };
+        Feed {
+            path,
+            atom,
+            title,
+            author,
+        }
+    }
+}
+
+// Includes are turned on with true, which uses the default directive, or by
+// giving the line prefix to look for.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Include {
+    Enabled(bool),
+    Directive(String),
+}
+
+// Markdown conversion is either turned on or off or configured with a table.
+#[cfg(feature = "markdown")]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "MarkdownEntry")]
+pub struct Markdown {
+    pub enabled: bool,
+    // Suffix added to a markdown file's path to get it unconverted.
+    pub raw: String,
+}
+
+#[cfg(feature = "markdown")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarkdownEntry {
+    Enabled(bool),
+    Table {
+        enabled: Option<bool>,
+        raw: Option<String>,
+    },
+}
+
+#[cfg(feature = "markdown")]
+impl From<MarkdownEntry> for Markdown {
+    fn from(m: MarkdownEntry) -> Self {
+        let (enabled, raw) = match m {
+            MarkdownEntry::Enabled(enabled) => (enabled, None),
+            MarkdownEntry::Table { enabled, raw } => (enabled.unwrap_or(true), raw),
+        };
+        Markdown {
+            enabled,
+            raw: raw.unwrap_or_else(|| ".raw".to_string()),
+        }
+    }
+}
+
+// Path rules for templates either turn them off or pick other fragments.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "TemplateEntry")]
+pub struct Template {
+    pub enabled: bool,
+    pub header: Option<String>,
+    pub footer: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TemplateEntry {
+    Enabled(bool),
+    Table {
+        header: Option<String>,
+        footer: Option<String>,
+    },
+}
+
+impl From<TemplateEntry> for Template {
+    fn from(t: TemplateEntry) -> Self {
+        match t {
+            TemplateEntry::Enabled(enabled) => Template {
+                enabled,
+                header: None,
+                footer: None,
+            },
+            TemplateEntry::Table { header, footer } => Template {
+                enabled: true,
+                header,
+                footer,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Cache {
+    // How many files are kept. The least recently used is dropped first.
+    pub entries: usize,
+    // Files larger than this many bytes aren't cached.
+    pub max_size: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Access {
+    pub allow: Option<Vec<access::Cidr>>,
+    pub deny: Option<Vec<access::Cidr>>,
+    pub status: Option<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerCfg {
     //    pub port: u16,
     pub server: Server,
+    pub ratelimit: Option<RateLimit>,
+    pub limiter: Arc<ratelimit::Limiter>,
+    pub ban: Option<Ban>,
+    pub bans: Arc<ban::Bans>,
+    pub cache: Option<Cache>,
+    pub files: Arc<cache::FileCache>,
+    pub titles: Arc<dirlist::Titles>,
+    pub feeds: Arc<feed::Feeds>,
+    #[cfg(feature = "markdown")]
+    pub pages: Arc<markdown::Pages>,
+    pub overrides: Arc<overrides::Overrides>,
+}
+
+// State that outlives a config and is shared by every vhost.
+#[derive(Debug, Default, Clone)]
+pub struct Shared {
+    pub limiter: Arc<ratelimit::Limiter>,
+    pub bans: Arc<ban::Bans>,
+    pub files: Arc<cache::FileCache>,
+    pub titles: Arc<dirlist::Titles>,
+    pub feeds: Arc<feed::Feeds>,
+    #[cfg(feature = "markdown")]
+    pub pages: Arc<markdown::Pages>,
+    pub overrides: Arc<overrides::Overrides>,
+}
+
+impl Shared {
+    // Drop what was cached under the old config. Limits and bans are kept.
+    pub fn clear_caches(&self) {
+        self.files.clear();
+        self.titles.clear();
+        self.feeds.clear();
+        self.overrides.clear();
+        #[cfg(feature = "markdown")]
+        self.pages.clear();
+    }
 }
 
 impl Config {
@@ -67,12 +499,108 @@ impl Config {
             p.push(&args[1]);
         }
 
-        let fd = fs::read_to_string(p).await.unwrap();
+        let fd = match fs::read_to_string(&p).await {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(Box::new(errors::GemError(format!(
+                    "{}: {}",
+                    p.display(),
+                    e
+                ))))
+            }
+        };
         let mut config: Config = match toml::from_str(&fd) {
             Ok(c) => c,
-            Err(e) => return Err(Box::new(e)),
+            Err(e) => {
+                return Err(Box::new(errors::GemError(format!(
+                    "{}: {}",
+                    p.display(),
+                    e
+                ))))
+            }
         };
 
+        let mut limits: Vec<&RateLimit> = config.ratelimit.iter().collect();
+        for srv in &config.server {
+            if let Some(r) = &srv.ratelimit_path {
+                limits.extend(r.values());
+            }
+        }
+        if limits.iter().any(|r| r.rate <= 0.0 || r.burst == 0) {
+            return Err(Box::new(errors::GemError(
+                "ratelimit needs a rate above 0 and a burst of at least 1".into(),
+            )));
+        }
+
+        if let Some(b) = &config.ban {
+            if b.limit == 0 {
+                return Err(Box::new(errors::GemError(
+                    "ban limit must be at least 1".into(),
+                )));
+            }
+        }
+
+        if let Some(srv) = config.server.iter().find(|s| s.dir.0.is_empty()) {
+            return Err(Box::new(errors::GemError(format!(
+                "{}: dir needs at least one directory",
+                srv.hostname
+            ))));
+        }
+
+        // CGI scripts are run from the disk so they can't be in an archive.
+        #[cfg(feature = "cgi")]
+        if let Some(srv) = config
+            .server
+            .iter()
+            .find(|s| s.cgi == Some(true) && s.dir.0.iter().any(|d| vfs::in_memory(d)))
+        {
+            return Err(Box::new(errors::GemError(format!(
+                "{}: cgi only works with document roots on disk",
+                srv.hostname
+            ))));
+        }
+
+        if let Some(srv) = config.server.iter().find(|s| !access::validate(s)) {
+            return Err(Box::new(errors::GemError(format!(
+                "{}: deny status must be a 4x or 5x status",
+                srv.hostname
+            ))));
+        }
+
+        for srv in config.server.iter_mut() {
+            srv.vfs = match vfs::Vfs::load(&srv.dir.0).await {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(Box::new(errors::GemError(format!(
+                        "{}: {}",
+                        srv.hostname, e
+                    ))))
+                }
+            };
+            let mut files: Vec<String> = srv
+                .header
+                .iter()
+                .chain(srv.footer.iter())
+                .cloned()
+                .collect();
+            if let Some(rules) = &srv.template_path {
+                for r in rules.values() {
+                    files.extend(r.header.iter().chain(r.footer.iter()).cloned());
+                }
+            }
+            for f in files {
+                match fs::read_to_string(&f).await {
+                    Ok(s) => srv.fragments.insert(f, s),
+                    Err(e) => {
+                        return Err(Box::new(errors::GemError(format!(
+                            "{}: {}: {}",
+                            srv.hostname, f, e
+                        ))))
+                    }
+                };
+            }
+        }
+
         if config.host.is_some() || config.port.is_some() {
             eprintln!(
                 "The host/port keys are depricated in favor \
@@ -80,48 +608,73 @@ impl Config {
             );
         }
 
-        if config.interface.is_some() && (config.host.is_some() || config.port.is_some()) {
-            return Err(Box::new(errors::GemError(
-                "You need to specify either host/port or interface".into(),
-            )));
-        } else if config.interface.is_none() && config.host.is_none() && config.port.is_none() {
+        if (config.interface.is_some() && (config.host.is_some() || config.port.is_some()))
+            || (config.interface.is_none() && config.host.is_none() && config.port.is_none())
+        {
             return Err(Box::new(errors::GemError(
                 "You need to specify either host/port or interface".into(),
             )));
-        } else if config.host.is_some() && config.port.is_some() {
-            let mut addr: Vec<std::net::SocketAddr> = Vec::new();
-            addr.push(
-                format!(
-                    "{}:{}",
-                    &config.host.to_owned().unwrap(),
-                    &config.port.unwrap()
-                )
+        } else if let (Some(host), Some(port)) = (&config.host, config.port) {
+            let addr = format!("{}:{}", host, port)
                 .to_socket_addrs()?
                 .next()
-                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?,
-            );
-            config.interface = Some(addr);
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+            config.interface = Some(vec![Interface {
+                addr,
+                proxy_protocol: false,
+            }]);
             return Ok(config);
         } else if let Some(ref mut i) = config.interface {
-            i.sort_by(|a, b| a.port().cmp(&b.port()));
-            i.dedup();
+            i.sort_by_key(|a| a.addr.port());
+            i.dedup_by_key(|a| a.addr);
             return Ok(config);
         }
         Err(Box::new(errors::GemError(
             "You need to specify either host/port or interface".into(),
         )))
     }
-    pub fn to_map(&self) -> HashMap<String, ServerCfg> {
+    pub fn to_map(&self, shared: &Shared) -> HashMap<String, Arc<ServerCfg>> {
         let mut map = HashMap::new();
         for srv in &self.server {
             map.insert(
                 srv.hostname.clone(),
-                ServerCfg {
+                Arc::new(ServerCfg {
                     //    port: self.port.clone(),
                     server: srv.clone(),
-                },
+                    ratelimit: self.ratelimit.clone(),
+                    limiter: shared.limiter.clone(),
+                    ban: self.ban.clone(),
+                    bans: shared.bans.clone(),
+                    cache: self.cache.clone(),
+                    files: shared.files.clone(),
+                    titles: shared.titles.clone(),
+                    feeds: shared.feeds.clone(),
+                    #[cfg(feature = "markdown")]
+                    pages: shared.pages.clone(),
+                    overrides: shared.overrides.clone(),
+                }),
             );
         }
         map
     }
 }
+
+// A config with one vhost for example.com with settings added to or
+// replacing its own.
+#[cfg(test)]
+pub fn example(settings: &str) -> Config {
+    let mut cfg: toml::Value = toml::from_str(
+        r#"
+        interface = [ "127.0.0.1:1965" ]
+        [[server]]
+        hostname = "example.com"
+        dir = "/nonexistent"
+        key = "key.pem"
+        cert = "cert.pem"
+        "#,
+    )
+    .unwrap();
+    let settings: toml::value::Table = toml::from_str(settings).unwrap();
+    cfg["server"][0].as_table_mut().unwrap().extend(settings);
+    cfg.try_into().unwrap()
+}
@@ -0,0 +1,244 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use crate::config;
+use crate::overrides;
+use crate::table::Table;
+use crate::util;
+use crate::vfs::{Stat, Vfs};
+
+type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    // Alphabetical.
+    #[default]
+    Name,
+    // Newest first.
+    Mtime,
+    // Largest first.
+    Size,
+}
+
+// How much of a file is searched for its title.
+const TITLE_SCAN: u64 = 8 * 1024;
+
+// Titles of gemtext files keyed by path along with the mtime they were read
+// at so each file is only read again after it changes.
+#[derive(Debug, Default)]
+pub struct Titles {
+    table: Table<PathBuf, (SystemTime, Option<String>)>,
+}
+
+impl Titles {
+    pub fn clear(&self) {
+        self.table.clear();
+    }
+
+    pub async fn get(&self, vfs: &Vfs, path: PathBuf, st: &Stat) -> Option<String> {
+        let modified = st.mtime?;
+        if let Some(title) = self.table.fresh(&path, &modified) {
+            return title;
+        }
+        let title = read_title(vfs, &path).await;
+        self.table.store(path, modified, title.clone());
+        title
+    }
+}
+
+// The first level one heading outside of preformatted text.
+async fn read_title(vfs: &Vfs, path: &Path) -> Option<String> {
+    let fd = vfs.open(path).await.ok()?;
+    let mut lines = BufReader::new(fd.take(TITLE_SCAN)).lines();
+    let mut pre = false;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.starts_with("```") {
+            pre = !pre;
+        } else if !pre && line.starts_with("# ") {
+            let title = line[2..].trim();
+            if !title.is_empty() {
+                return Some(title.to_string());
+            }
+        }
+    }
+    None
+}
+
+pub fn is_gemtext(name: &str) -> bool {
+    name.ends_with(".gmi") || name.ends_with(".gemini")
+}
+
+// The longest matching path rule wins over the server's.
+fn rule<'a>(srv: &'a config::Server, url: &url::Url) -> Option<&'a config::DirList> {
+    util::path_rule(srv.dirlist_path.as_ref(), &util::rule_path(url))
+        .map(|(_, r)| r)
+        .or(srv.dirlist.as_ref())
+}
+
+fn human_size(len: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if len < 1024 {
+        return format!("{} B", len);
+    }
+    let mut size = len as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn mtime(st: &Stat) -> u64 {
+    st.mtime
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+async fn read_lossy(vfs: &Vfs, path: &Path) -> Option<String> {
+    let bytes = vfs.read(path).await.ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// The contents of the first layer's file called name.
+async fn first(vfs: &Vfs, paths: &[PathBuf], name: &str) -> Option<String> {
+    for path in paths {
+        if let Some(s) = read_lossy(vfs, &path.join(name)).await {
+            return Some(s);
+        }
+    }
+    None
+}
+
+// Generate the listing for the directory at paths, one for each document root
+// layer it's in, or None if listings are turned off for it. An entry in an
+// earlier layer hides one with the same name in a later one. A dirlist set in
+// the directory's .gemserv.toml wins over the server's rules.
+pub async fn list(
+    srv: &config::ServerCfg,
+    paths: &[PathBuf],
+    u: &url::Url,
+    over: Option<&config::DirList>,
+) -> Result<Option<String>> {
+    let default = config::DirList::default();
+    let rule = match over.or_else(|| rule(&srv.server, u)) {
+        Some(r) if !r.enabled => return Ok(None),
+        Some(r) => r,
+        None => &default,
+    };
+
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let vfs = &srv.server.vfs;
+    let mut seen = HashSet::new();
+    for path in paths {
+        for (name, m) in vfs.list(path).await? {
+            if !seen.insert(name.clone()) || name == overrides::FILE {
+                continue;
+            }
+            if m.mode & 0o0444 != 0o0444 {
+                continue;
+            }
+            if name.starts_with('.') && !rule.dotfiles {
+                continue;
+            }
+            if rule.exclude.iter().any(|p| util::glob(p, &name)) {
+                continue;
+            }
+            if m.is_dir() {
+                dirs.push((path, name, m));
+            } else {
+                files.push((path, name, m));
+            }
+        }
+    }
+
+    for entries in [&mut dirs, &mut files] {
+        match rule.sort {
+            Sort::Name => entries.sort_by(|a, b| a.1.cmp(&b.1)),
+            Sort::Mtime => entries.sort_by_key(|e| Reverse(mtime(&e.2))),
+            Sort::Size => entries.sort_by_key(|e| Reverse(e.2.len)),
+        }
+    }
+
+    let mut list = match first(vfs, paths, ".header.gmi").await {
+        Some(h) => h,
+        None => format!("# Directory Listing\r\n\r\nPath: {}\r\n\r\n", u.path()),
+    };
+    if !list.is_empty() && !list.ends_with('\n') {
+        list.push_str("\r\n");
+    }
+
+    // Links are relative to the directory even when it was asked for without
+    // the trailing slash.
+    let mut base = u.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", u.path()));
+    }
+
+    for (path, name, m) in dirs.iter().chain(files.iter()) {
+        let slash = if m.is_dir() { "/" } else { "" };
+        let link = format!("{}{}", name, slash);
+        // Keep names with a colon from being read as a scheme.
+        let ep = match base.join(&format!("./{}", link)) {
+            Ok(p) => p,
+            _ => continue,
+        };
+        let mut label = link.clone();
+        if rule.titles && !m.is_dir() && is_gemtext(name) {
+            if let Some(t) = srv.titles.get(vfs, path.join(name), m).await {
+                label = t;
+            }
+        }
+        let mut details = Vec::new();
+        if rule.size && !m.is_dir() {
+            details.push(human_size(m.len));
+        }
+        if rule.date {
+            details.push(util::date(mtime(m)));
+        }
+        if details.is_empty() {
+            list.push_str(&format!("=> {} {}\r\n", ep, label));
+        } else {
+            list.push_str(&format!("=> {} {} ({})\r\n", ep, label, details.join(", ")));
+        }
+    }
+
+    if let Some(f) = first(vfs, paths, ".footer.gmi").await {
+        list.push_str("\r\n");
+        list.push_str(&f);
+    }
+
+    Ok(Some(list))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_rules_win_over_the_server() {
+        let srv = config::example(
+            r#"
+            dirlist = { sort = "mtime" }
+            dirlist_path = { "/drafts/" = false, "/files/" = { size = true } }
+            "#,
+        )
+        .server
+        .remove(0);
+        let rule = |path: &str| {
+            let url = url::Url::parse(&format!("gemini://example.com{}", path)).unwrap();
+            rule(&srv, &url).cloned().unwrap()
+        };
+        assert!(rule("/").enabled);
+        assert_eq!(rule("/").sort, Sort::Mtime);
+        assert!(!rule("/drafts/").enabled);
+        assert!(!rule("/drafts").enabled);
+        assert!(rule("/files/").size);
+        assert_eq!(rule("/files/").sort, Sort::Name);
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config;
+use crate::dirlist;
+use crate::status::Status;
+use crate::table::Table;
+use crate::util;
+
+#[derive(Debug, Clone)]
+struct Post {
+    name: String,
+    date: String,
+    title: String,
+}
+
+// The paths and mtimes of the posts a list was built from.
+type Stamp = Vec<(PathBuf, Option<SystemTime>)>;
+
+// Post lists keyed by the gemlog's directory in each layer so titles are only
+// looked up again once a post is added, removed or edited.
+#[derive(Debug, Default)]
+pub struct Feeds {
+    table: Table<Vec<PathBuf>, (Stamp, Vec<Post>)>,
+}
+
+impl Feeds {
+    pub fn clear(&self) {
+        self.table.clear();
+    }
+
+    // The posts in the gemlog's directories. A post in an earlier layer hides
+    // one with the same name in a later layer like it does when it's served.
+    async fn posts(&self, srv: &config::ServerCfg, dirs: &[PathBuf]) -> io::Result<Vec<Post>> {
+        let vfs = &srv.server.vfs;
+        let mut names = Vec::new();
+        let mut found = Vec::new();
+        let mut listed = false;
+        for dir in dirs.iter().filter(|d| vfs.is_dir(d)) {
+            for (name, m) in vfs.list(dir).await? {
+                if names.contains(&name) {
+                    continue;
+                }
+                names.push(name.clone());
+                if is_post(&name) && m.is_file() && m.mode & 0o0444 == 0o0444 {
+                    found.push((dir.join(&name), name, m));
+                }
+            }
+            listed = true;
+        }
+        if !listed {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        // Newest first.
+        found.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let stamp: Stamp = found.iter().map(|(p, _, m)| (p.clone(), m.mtime)).collect();
+        if let Some(posts) = self.table.fresh(dirs, &stamp) {
+            return Ok(posts);
+        }
+
+        let mut posts = Vec::new();
+        for (path, name, m) in found {
+            let title = match srv.titles.get(vfs, path, &m).await {
+                Some(t) => t,
+                None => fallback_title(&name),
+            };
+            posts.push(Post {
+                date: name[..10].to_string(),
+                name,
+                title,
+            });
+        }
+        self.table.store(dirs.to_vec(), stamp, posts.clone());
+        Ok(posts)
+    }
+}
+
+// Posts are gemtext files named like 2022-02-09-title.gmi.
+fn is_post(name: &str) -> bool {
+    let date = match name.get(..10) {
+        Some(d) => d,
+        None => return false,
+    };
+    dirlist::is_gemtext(name)
+        && date.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+// Turn 2022-02-09-hello-world.gmi into "hello world".
+fn fallback_title(name: &str) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(s, _)| s);
+    let title = stem[10..].trim_matches('-').replace('-', " ");
+    if title.is_empty() {
+        stem[..10].to_string()
+    } else {
+        title
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A post's URL. Names are encoded the way directory listings encode them and
+// one with a colon isn't read as a scheme.
+fn link(base: &url::Url, post: &Post) -> Option<url::Url> {
+    base.join(&format!("./{}", post.name)).ok()
+}
+
+fn index(title: &str, base: &url::Url, posts: &[Post]) -> String {
+    let mut page = format!("# {}\r\n\r\n", title);
+    for p in posts {
+        if let Some(link) = link(base, p) {
+            page.push_str(&format!("=> {} {} - {}\r\n", link, p.date, p.title));
+        }
+    }
+    page
+}
+
+fn atom(about: &About, base: &url::Url, self_url: &url::Url, posts: &[Post]) -> String {
+    let updated = posts.first().map_or("1970-01-01", |p| p.date.as_str());
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <title>{}</title>\n", escape(about.title)));
+    xml.push_str(&format!(
+        "  <author>\n    <name>{}</name>\n  </author>\n",
+        escape(about.author)
+    ));
+    xml.push_str(&format!("  <id>{}</id>\n", escape(base.as_str())));
+    xml.push_str(&format!(
+        "  <link href=\"{}\" rel=\"self\"/>\n",
+        escape(self_url.as_str())
+    ));
+    xml.push_str(&format!(
+        "  <link href=\"{}\" rel=\"alternate\"/>\n",
+        escape(base.as_str())
+    ));
+    xml.push_str(&format!("  <updated>{}T00:00:00Z</updated>\n", updated));
+    for p in posts {
+        let link = match link(base, p) {
+            Some(l) => l,
+            None => continue,
+        };
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&p.title)));
+        xml.push_str(&format!("    <id>{}</id>\n", escape(link.as_str())));
+        xml.push_str(&format!(
+            "    <link href=\"{}\" rel=\"alternate\"/>\n",
+            escape(link.as_str())
+        ));
+        xml.push_str(&format!("    <updated>{}T00:00:00Z</updated>\n", p.date));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+// The title and author of a feed.
+struct About<'a> {
+    title: &'a str,
+    author: &'a str,
+}
+
+// Generate the gemlog index or Atom feed if url is one of them. Returns the
+// mime type and body, or the status to answer with if the gemlog can't be read.
+pub async fn check(
+    srv: &config::ServerCfg,
+    url: &url::Url,
+) -> Result<Option<(&'static str, String)>, Status> {
+    let feed = match &srv.server.feed {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let path = util::rule_path(url);
+    let is_atom = path == feed.atom;
+    if !is_atom && path != feed.path {
+        return Ok(None);
+    }
+
+    let rel = feed.path.trim_start_matches('/');
+    let dirs: Vec<PathBuf> = srv
+        .server
+        .dir
+        .0
+        .iter()
+        .map(|d| Path::new(d).join(rel))
+        .collect();
+    let posts = match srv.feeds.posts(srv, &dirs).await {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("Gemlog {}: {}", feed.path, e);
+            return match e.kind() {
+                io::ErrorKind::NotFound => Err(Status::NotFound),
+                _ => Err(Status::TemporaryFailure),
+            };
+        }
+    };
+    let hostname = &srv.server.hostname;
+    let about = About {
+        title: feed.title.as_deref().unwrap_or(hostname),
+        author: feed.author.as_deref().unwrap_or(hostname),
+    };
+    let base = url.join(&feed.path).map_err(|_| Status::BadRequest)?;
+
+    if is_atom {
+        Ok(Some((
+            "application/atom+xml",
+            atom(&about, &base, url, &posts),
+        )))
+    } else {
+        Ok(Some(("text/gemini", index(about.title, &base, &posts))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A fresh directory for a test's files.
+    fn tmp(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gemserv-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn get(settings: &str, path: &str) -> Result<Option<String>, u8> {
+        let vhosts = config::example(settings).to_map(&config::Shared::default());
+        let url = url::Url::parse(&format!("gemini://example.com{}", path)).unwrap();
+        match check(&vhosts["example.com"], &url).await {
+            Ok(page) => Ok(page.map(|(_, body)| body)),
+            Err(s) => Err(s as u8),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_gemlog_is_not_found() {
+        for path in ["/gemlog/", "/gemlog/atom.xml"] {
+            let res = get(r#"feed = "/gemlog/""#, path).await;
+            assert_eq!(res, Err(Status::NotFound as u8), "{}", path);
+        }
+        assert_eq!(get(r#"feed = "/gemlog/""#, "/other/").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn posts_from_every_layer() {
+        let top = tmp("feed-top");
+        let bottom = tmp("feed-bottom");
+        for (dir, name, body) in [
+            (&top, "2022-02-09-hello world.gmi", "# Hello"),
+            (&top, "2022-02-10-shared.gmi", "# From the top"),
+            (&bottom, "2022-02-10-shared.gmi", "# From the bottom"),
+            (&bottom, "2022-02-11-later.gmi", "# Later"),
+            (&bottom, "notes.gmi", "# Not a post"),
+        ] {
+            fs::create_dir_all(dir.join("gemlog")).unwrap();
+            fs::write(dir.join("gemlog").join(name), body).unwrap();
+        }
+        let settings = format!(
+            r#"
+            dir = [ "{}", "{}" ]
+            feed = {{ path = "/gemlog/", title = "Log", author = "Someone" }}
+            "#,
+            top.display(),
+            bottom.display()
+        );
+
+        let index = get(&settings, "/%67emlog/").await.unwrap().unwrap();
+        assert_eq!(
+            index,
+            "# Log\r\n\r\n\
+            => gemini://example.com/gemlog/2022-02-11-later.gmi 2022-02-11 - Later\r\n\
+            => gemini://example.com/gemlog/2022-02-10-shared.gmi 2022-02-10 - From the top\r\n\
+            => gemini://example.com/gemlog/2022-02-09-hello%20world.gmi 2022-02-09 - Hello\r\n"
+        );
+
+        let atom = get(&settings, "/gemlog/atom.xml").await.unwrap().unwrap();
+        assert!(atom.contains("<author>\n    <name>Someone</name>\n  </author>"));
+        assert!(atom.contains("<id>gemini://example.com/gemlog/2022-02-09-hello%20world.gmi</id>"));
+        assert_eq!(atom.matches("<entry>").count(), 3);
+    }
+}
@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use crate::con_handler;
+use crate::config;
+
+// How deep includes can nest before the rest are left out.
+const MAX_DEPTH: usize = 8;
+
+// Line prefix that includes another file when includes are turned on.
+const DIRECTIVE: &str = "=> include:";
+
+fn directive(srv: &config::Server) -> Option<&str> {
+    match &srv.include {
+        Some(config::Include::Enabled(true)) => Some(DIRECTIVE),
+        Some(config::Include::Directive(d)) => Some(d.as_str()),
+        _ => None,
+    }
+}
+
+// Where an include points. Absolute targets are inside root and relative
+// ones next to the including file. Anything resolving outside root or that
+// wouldn't be served itself, like CGI scripts or files that aren't world
+// readable, is refused. The rules are checked against the path as written
+// and the file it resolves to so a symlink can't get around them.
+async fn resolve(srv: &config::Server, root: &Path, from: &Path, target: &str) -> Option<PathBuf> {
+    let target = target.trim();
+    let joined = match target.strip_prefix('/') {
+        Some(t) => root.join(t),
+        None => from.parent()?.join(target),
+    };
+    let path = srv.vfs.canonicalize(&joined).await.ok()?;
+    if !path.starts_with(root) {
+        return None;
+    }
+    let meta = srv.vfs.stat(&path).await.ok()?;
+    if !meta.is_file()
+        || con_handler::hidden(srv, &joined, &meta)
+        || con_handler::hidden(srv, &path, &meta)
+    {
+        return None;
+    }
+    Some(path)
+}
+
+fn expand_lines<'a>(
+    srv: &'a config::Server,
+    prefix: &'a str,
+    root: &'a Path,
+    stack: &'a mut Vec<PathBuf>,
+    body: Vec<u8>,
+) -> Pin<Box<dyn Future<Output = (Vec<u8>, bool)> + Send + Sync + 'a>> {
+    Box::pin(async move {
+        let mut out = Vec::with_capacity(body.len());
+        let mut found = false;
+        for line in body.split_inclusive(|b| *b == b'\n') {
+            let text = std::str::from_utf8(line).unwrap_or("");
+            let target = match text.trim_end().strip_prefix(prefix) {
+                Some(t) => t,
+                None => {
+                    out.extend_from_slice(line);
+                    continue;
+                }
+            };
+            found = true;
+
+            let from = stack.last().cloned().unwrap_or_default();
+            let path = match resolve(srv, root, &from, target).await {
+                Some(p) => p,
+                None => {
+                    log::error!("Include {} from {} not found", target, from.display());
+                    continue;
+                }
+            };
+            if stack.contains(&path) {
+                let chain: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+                log::error!(
+                    "Include cycle: {} -> {}",
+                    chain.join(" -> "),
+                    path.display()
+                );
+                continue;
+            }
+            if stack.len() > MAX_DEPTH {
+                log::error!(
+                    "Include of {} from {} nested more than {} deep",
+                    path.display(),
+                    from.display(),
+                    MAX_DEPTH
+                );
+                continue;
+            }
+            let included = match srv.vfs.read(&path).await {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Include {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            stack.push(path);
+            let (included, _) = expand_lines(srv, prefix, root, stack, included).await;
+            stack.pop();
+            out.extend_from_slice(&included);
+            if !included.is_empty() && !included.ends_with(b"\n") {
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+        (out, found)
+    })
+}
+
+// Expands the includes of one gemtext page.
+pub struct Expander<'a> {
+    srv: &'a config::Server,
+    prefix: &'a str,
+    root: PathBuf,
+    file: PathBuf,
+}
+
+impl Expander<'_> {
+    // The files included by line if it's a directive.
+    pub async fn line(&self, line: &[u8]) -> Option<Vec<u8>> {
+        let text = std::str::from_utf8(line).ok()?;
+        text.trim_end().strip_prefix(self.prefix)?;
+        let mut stack = vec![self.file.clone()];
+        let (included, _) =
+            expand_lines(self.srv, self.prefix, &self.root, &mut stack, line.to_vec()).await;
+        Some(included)
+    }
+}
+
+// An expander for the gemtext page at file, or None if includes are off.
+pub async fn expander<'a>(
+    srv: &'a config::Server,
+    root: &Path,
+    file: &Path,
+) -> Option<Expander<'a>> {
+    let prefix = directive(srv)?;
+    let vfs = &srv.vfs;
+    let (root, file) = match (vfs.canonicalize(root).await, vfs.canonicalize(file).await) {
+        (Ok(r), Ok(f)) => (r, f),
+        _ => return None,
+    };
+    Some(Expander {
+        srv,
+        prefix,
+        root,
+        file,
+    })
+}
+
+// Inline the files included by the gemtext page at file. Returns the page and
+// whether it included anything. Includes that can't be read, loop back on
+// themselves or nest too deep are logged and left out.
+pub async fn expand(
+    srv: &config::Server,
+    root: &Path,
+    file: &Path,
+    body: Vec<u8>,
+) -> (Vec<u8>, bool) {
+    match expander(srv, root, file).await {
+        Some(e) => {
+            let mut stack = vec![e.file];
+            expand_lines(srv, e.prefix, &e.root, &mut stack, body).await
+        }
+        None => (body, false),
+    }
+}
+
+// Whether gemtext pages on this server are checked for includes.
+pub fn enabled(srv: &config::Server) -> bool {
+    directive(srv).is_some()
+}
@@ -1,19 +1,21 @@
 use std::io;
 use std::marker::Unpin;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use tokio::io::AsyncRead;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
+use crate::ban;
 use crate::status::Status;
 
 pub struct Connection {
     pub stream: TlsStream<TcpStream>,
     pub local_addr: SocketAddr,
     pub peer_addr: SocketAddr,
-    pub srv: crate::config::ServerCfg,
+    pub srv: Arc<crate::config::ServerCfg>,
 }
 
 impl Connection {
@@ -28,6 +30,7 @@ impl Connection {
         meta: Option<&str>,
         body: Option<String>,
     ) -> Result<(), io::Error> {
+        ban::record(&self.srv, self.peer_addr, stat);
         let meta = match meta {
             Some(m) => m,
             None => stat.to_str(),
@@ -1,6 +1,8 @@
 pub mod conn;
 pub mod errors;
+pub mod proxy_protocol;
 pub mod server;
 pub mod status;
+pub mod table;
 pub mod tls;
 pub mod util;
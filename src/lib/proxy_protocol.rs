@@ -0,0 +1,265 @@
+// PROXY protocol as described in
+// https://www.haproxy.org/download/2.5/doc/proxy-protocol.txt
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIG: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX: usize = 107;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol: {}", msg),
+    )
+}
+
+// Read the header off the stream without touching anything after it. Returns
+// the client and destination addresses or None if the sender asked for the
+// connection's own addresses to be used, like load balancer health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    // Both versions are at least 12 bytes long.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIG {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing header"));
+    }
+
+    // The v1 header is a single line so read it a byte at a time so the TLS
+    // handshake after it is left alone.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid("header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("header isn't ascii"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {}
+        _ => return Err(invalid("bad v1 header")),
+    }
+    let ip = |s: &str| s.parse::<IpAddr>().map_err(|_| invalid("bad address"));
+    let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("bad port"));
+    let src = SocketAddr::new(ip(parts[2])?, port(parts[4])?);
+    let dst = SocketAddr::new(ip(parts[3])?, port(parts[5])?);
+    if src.is_ipv4() != (parts[1] == "TCP4") || dst.is_ipv4() != src.is_ipv4() {
+        return Err(invalid("address family mismatch"));
+    }
+    Ok(Some((src, dst)))
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let ver_cmd = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match family >> 4 {
+        // AF_INET
+        0x1 if body.len() >= 12 => {
+            let mut src = [0u8; 4];
+            let mut dst = [0u8; 4];
+            src.copy_from_slice(&body[0..4]);
+            dst.copy_from_slice(&body[4..8]);
+            Ok(Some((
+                SocketAddr::new(Ipv4Addr::from(src).into(), port(&body[8..10])),
+                SocketAddr::new(Ipv4Addr::from(dst).into(), port(&body[10..12])),
+            )))
+        }
+        // AF_INET6
+        0x2 if body.len() >= 36 => {
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&body[0..16]);
+            dst.copy_from_slice(&body[16..32]);
+            Ok(Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), port(&body[32..34])),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), port(&body[34..36])),
+            )))
+        }
+        // AF_UNSPEC
+        0x0 => Ok(None),
+        _ => Err(invalid("unsupported address family")),
+    }
+}
+
+#[cfg(feature = "proxy")]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+// Build a header telling the receiver the connection came from src to dst.
+#[cfg(feature = "proxy")]
+pub fn header(version: Version, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (
+            SocketAddr::new(s.into(), src.port()),
+            SocketAddr::new(d.into(), dst.port()),
+        ),
+        // Mixed families can only be sent as IPv6.
+        (s, d) => (
+            SocketAddr::new(to_v6(s).into(), src.port()),
+            SocketAddr::new(to_v6(d).into(), dst.port()),
+        ),
+    };
+
+    match version {
+        Version::V1 => {
+            let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                proto,
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut h = V2_SIG.to_vec();
+            // Version 2, PROXY command.
+            h.push(0x21);
+            match (src.ip(), dst.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    h.push(0x11);
+                    h.extend_from_slice(&12u16.to_be_bytes());
+                    h.extend_from_slice(&s.octets());
+                    h.extend_from_slice(&d.octets());
+                }
+                (s, d) => {
+                    h.push(0x21);
+                    h.extend_from_slice(&36u16.to_be_bytes());
+                    h.extend_from_slice(&to_v6(s).octets());
+                    h.extend_from_slice(&to_v6(d).octets());
+                }
+            }
+            h.extend_from_slice(&src.port().to_be_bytes());
+            h.extend_from_slice(&dst.port().to_be_bytes());
+            h
+        }
+    }
+}
+
+#[cfg(feature = "proxy")]
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+        read_header(&mut bytes).await
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut h = V2_SIG.to_vec();
+        h.push(ver_cmd);
+        h.push(family);
+        h.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        h.extend_from_slice(body);
+        h
+    }
+
+    fn addrs(src: &str, dst: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn reads_headers() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 1965\r\n\x16\x03";
+        let header = read_header(&mut stream).await.unwrap();
+        assert_eq!(header, addrs("192.0.2.1:5000", "192.0.2.2:1965"));
+        // The TLS handshake after the header is left for the acceptor.
+        assert_eq!(stream, b"\x16\x03");
+
+        let header = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 1965\r\n").await;
+        assert_eq!(
+            header.unwrap(),
+            addrs("[2001:db8::1]:5000", "[2001:db8::2]:1965")
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+
+        let body = [192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x07, 0xad];
+        let header = read(&v2(0x21, 0x11, &body)).await.unwrap();
+        assert_eq!(header, addrs("192.0.2.1:5000", "192.0.2.2:1965"));
+        // LOCAL connections keep their own addresses.
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_headers() {
+        let ipv4 = [192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x07, 0xad];
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        long.extend_from_slice(b"\r\n");
+        let mut truncated = v2(0x21, 0x11, &ipv4);
+        truncated.truncate(20);
+
+        let bad: [(&str, &[u8]); 12] = [
+            (
+                "missing header",
+                b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00",
+            ),
+            ("plain text", b"GET / HTTP/1.0\r\n\r\n"),
+            ("garbage v1", b"PROXY TCP4 nonsense\r\n"),
+            ("bad address", b"PROXY TCP4 192.0.2.1 example 5000 1965\r\n"),
+            ("bad port", b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 99999\r\n"),
+            (
+                "family mismatch",
+                b"PROXY TCP4 2001:db8::1 2001:db8::2 5000 1965\r\n",
+            ),
+            ("oversize v1", &long),
+            (
+                "unterminated v1",
+                b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 1965",
+            ),
+            ("truncated v2", &truncated),
+            ("v2 version", &v2(0x11, 0x11, &ipv4)),
+            ("v2 command", &v2(0x22, 0x11, &ipv4)),
+            ("v2 family", &v2(0x21, 0x31, &ipv4)),
+        ];
+        for (name, bytes) in bad {
+            assert!(read(bytes).await.is_err(), "{}", name);
+        }
+        // An address block too short for its family.
+        assert!(read(&v2(0x21, 0x21, &ipv4)).await.is_err());
+    }
+}
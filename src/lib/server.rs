@@ -1,21 +1,29 @@
 #![allow(unreachable_code)]
+use arc_swap::ArcSwap;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
-use tokio::sync::watch::Receiver;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch::{self, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
 
 use std::collections::HashMap;
 use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
+use crate::ban;
 use crate::config;
 use crate::conn;
 use crate::errors::{GemError, Result};
 use crate::logger;
+use crate::proxy_protocol;
 use crate::status::Status;
 
 pub trait Handler:
@@ -40,103 +48,305 @@ where
     move |a, b| Box::pin(f(a, b)) as _
 }
 
-pub struct Server {
-    pub listener: Vec<TcpListener>,
+// Everything a new connection needs from the config. It's replaced as a whole
+// on reload so a connection never sees the vhosts of one config and the
+// certificates of another.
+pub struct Snapshot {
+    pub cmap: HashMap<String, Arc<config::ServerCfg>>,
     pub acceptor: TlsAcceptor,
 }
 
-impl Server {
-    pub async fn bind(
-        addr: Vec<std::net::SocketAddr>,
-        acceptor: fn(config::Config) -> std::io::Result<TlsAcceptor>,
-        cfg: config::Config,
-    ) -> Result<Server> {
-        if addr.len() == 1 {
-            Ok(Server {
-                listener: vec![TcpListener::bind(addr[0].to_owned()).await?],
-                acceptor: acceptor(cfg)?,
-            })
-        } else {
-            let mut listener: Vec<TcpListener> = Vec::new();
-            for a in addr {
-                listener.append(&mut vec![TcpListener::bind(a.to_owned()).await?]);
+impl Snapshot {
+    // The vhost a connection for sni is handled by. Only a reference count
+    // changes so connections don't copy any of the config.
+    pub fn vhost(&self, sni: &str) -> Option<Arc<config::ServerCfg>> {
+        self.cmap.get(sni).cloned()
+    }
+}
+
+// Counts the connections being handled so shutdown can wait for them.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlight {
+    fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    async fn idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
             }
-            Ok(Server {
-                listener,
-                acceptor: acceptor(cfg)?,
-            })
-        }
-    }
-
-    pub async fn serve(
-        self,
-        cmap: HashMap<String, config::ServerCfg>,
-        handler: impl Handler + 'static + Copy,
-        shutdown: Receiver<bool>,
-    ) -> Result {
-        for listen in self.listener {
-            let cmap = cmap.clone();
-            let listen = Arc::new(listen);
-            let acceptor = Arc::new(self.acceptor.clone());
-            let mut shutdown = shutdown.clone();
-
-            tokio::spawn(async move {
-                loop {
-                    tokio::select! {
-                        _ = shutdown.changed() => {
-                            break
-                        }
-                        Ok((stream, peer_addr)) = listen.accept() => {
-                        let local_addr = stream.local_addr().unwrap();
-                        let acceptor = acceptor.clone();
-                        let cmap = cmap.clone();
-                        let mut handler = handler;
-
-                        tokio::spawn(async move {
-                            let mut stream = match acceptor.accept(stream).await {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    log::error!("Error: {}", e);
+            idle.await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+struct Listener {
+    listen: Arc<TcpListener>,
+    proxy_protocol: bool,
+    stop: Sender<bool>,
+    task: JoinHandle<Result>,
+}
+
+pub struct Server<H> {
+    listeners: HashMap<SocketAddr, Listener>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+    handler: H,
+    bans: Arc<ban::Bans>,
+    inflight: Arc<InFlight>,
+}
+
+impl<H: Handler + 'static> Server<H> {
+    pub fn new(snapshot: Snapshot, handler: H, bans: Arc<ban::Bans>) -> Server<H> {
+        Server {
+            listeners: HashMap::new(),
+            snapshot: Arc::new(ArcSwap::from_pointee(snapshot)),
+            handler,
+            bans,
+            inflight: Arc::new(InFlight::default()),
+        }
+    }
+
+    // Stop accepting and give the connections being handled up to drain to
+    // finish. Whatever is still running after that is dropped with the
+    // runtime.
+    pub async fn shutdown(mut self, drain: Duration) -> Result {
+        for (_, l) in self.listeners.drain() {
+            l.close().await?;
+        }
+        let count = self.inflight.count.load(Ordering::SeqCst);
+        if count == 0 {
+            return Ok(());
+        }
+        log::info!(
+            "Waiting up to {}s for {} connections",
+            drain.as_secs(),
+            count
+        );
+        match tokio::time::timeout(drain, self.inflight.idle()).await {
+            Ok(_) => log::info!("All connections finished"),
+            Err(_) => log::warn!(
+                "Dropping {} connections that didn't finish in time",
+                self.inflight.count.load(Ordering::SeqCst)
+            ),
+        }
+        Ok(())
+    }
+
+    // New connections use the new config from here on. Connections already
+    // being handled keep the one they started with.
+    pub fn swap(&self, snapshot: Snapshot) {
+        self.snapshot.store(Arc::new(snapshot));
+    }
+
+    // Bring the listeners in line with addr. Sockets on addresses that didn't
+    // change are kept open so no connection is refused during a reload.
+    pub async fn listen_on(&mut self, addr: Vec<config::Interface>) -> Result {
+        let removed: Vec<SocketAddr> = self
+            .listeners
+            .keys()
+            .filter(|a| !addr.iter().any(|i| i.addr == **a))
+            .copied()
+            .collect();
+        for a in removed {
+            if let Some(l) = self.listeners.remove(&a) {
+                l.close().await?;
+                log::info!("Stopped listening on {}", a);
+            }
+        }
+
+        let mut errors = Vec::new();
+        for i in addr {
+            let listen = match self.listeners.remove(&i.addr) {
+                Some(l) if l.proxy_protocol == i.proxy_protocol => {
+                    self.listeners.insert(i.addr, l);
+                    continue;
+                }
+                // Only the PROXY protocol setting changed so restart the
+                // accept loop on the same socket.
+                Some(l) => {
+                    let listen = l.listen.clone();
+                    l.close().await?;
+                    listen
+                }
+                None => match TcpListener::bind(i.addr).await {
+                    Ok(l) => {
+                        log::info!("Listening on {}", i.addr);
+                        Arc::new(l)
+                    }
+                    Err(e) => {
+                        errors.push(format!("{}: {}", i.addr, e));
+                        continue;
+                    }
+                },
+            };
+            let listener = self.listen(listen, i.proxy_protocol);
+            self.listeners.insert(i.addr, listener);
+        }
+
+        if !errors.is_empty() {
+            return Err(Box::new(GemError(format!(
+                "Couldn't listen on {}",
+                errors.join(", ")
+            ))));
+        }
+        Ok(())
+    }
+
+    fn listen(&self, listen: Arc<TcpListener>, proxy_protocol: bool) -> Listener {
+        let (stop, mut shutdown) = watch::channel(true);
+        let snapshot = self.snapshot.clone();
+        let handler = self.handler;
+        let bans = self.bans.clone();
+        let inflight = self.inflight.clone();
+        let accept_on = listen.clone();
+
+        let task = tokio::spawn(async move {
+            let errors = AtomicU64::new(0);
+            loop {
+                tokio::select! {
+                    _ = shutdown.changed() => {
+                        break
+                    }
+                    (mut stream, peer_addr, local_addr) = accept(&accept_on, &errors) => {
+                    if !proxy_protocol && bans.banned(peer_addr.ip()) {
+                        continue;
+                    }
+                    let snapshot = snapshot.load_full();
+                    let bans = bans.clone();
+                    let mut handler = handler;
+                    let guard = inflight.enter();
+
+                    tokio::spawn(async move {
+                        let _guard = guard;
+                        let (local_addr, peer_addr) = if proxy_protocol {
+                            let header = tokio::time::timeout(
+                                tokio::time::Duration::from_secs(5),
+                                proxy_protocol::read_header(&mut stream),
+                            )
+                            .await;
+                            match header {
+                                Ok(Ok(Some((src, dst)))) => (dst, src),
+                                Ok(Ok(None)) => (local_addr, peer_addr),
+                                Ok(Err(e)) => {
+                                    log::warn!("remote={} {}", peer_addr, e);
+                                    return Ok(());
+                                }
+                                Err(_) => {
+                                    log::warn!("remote={} PROXY protocol: timed out", peer_addr);
                                     return Ok(());
                                 }
-                            };
-                            let (_, sni) = TlsStream::get_mut(&mut stream);
-                            let sni = match sni.sni_hostname() {
-                                Some(s) => s,
-                                None => return Ok(()),
-                            };
-
-                            let srv = match cmap.get(sni) {
-                                Some(h) => h,
-                                None => return Ok(()) as io::Result<()>,
                             }
-                            .to_owned();
-
-                            let con = conn::Connection {
-                                stream,
-                                local_addr,
-                                peer_addr,
-                                srv,
-                            };
-                            let (con, url) = match get_request(con).await {
-                                Ok((c, u)) => (c, u),
-                                Err(_) => return Ok(()) as io::Result<()>,
-                            };
-
-                            match handler(con, url).await {
-                                Ok(o) => o,
-                                Err(_) => return Ok(()) as io::Result<()>,
+                        } else {
+                            (local_addr, peer_addr)
+                        };
+                        if proxy_protocol && bans.banned(peer_addr.ip()) {
+                            return Ok(());
+                        }
+
+                        let mut stream = match snapshot.acceptor.accept(stream).await {
+                            Ok(s) => s,
+                            Err(e) => {
+                                log::error!("Error: {}", e);
+                                return Ok(());
                             }
+                        };
+                        let (_, sni) = TlsStream::get_mut(&mut stream);
+                        let sni = match sni.sni_hostname() {
+                            Some(s) => s,
+                            None => return Ok(()),
+                        };
 
-                            Ok(())
-                        });
-                    }
-                    }
+                        let srv = match snapshot.vhost(sni) {
+                            Some(h) => h,
+                            None => return Ok(()) as io::Result<()>,
+                        };
+
+                        let con = conn::Connection {
+                            stream,
+                            local_addr,
+                            peer_addr,
+                            srv,
+                        };
+                        let (con, url) = match get_request(con).await {
+                            Ok((c, u)) => (c, u),
+                            Err(_) => return Ok(()) as io::Result<()>,
+                        };
+
+                        match handler(con, url).await {
+                            Ok(o) => o,
+                            Err(_) => return Ok(()) as io::Result<()>,
+                        }
+
+                        Ok(())
+                    });
+                }
                 }
-                Ok(()) as Result
-            });
+            }
+            Ok(()) as Result
+        });
+
+        Listener {
+            listen,
+            proxy_protocol,
+            stop,
+            task,
+        }
+    }
+}
+
+impl Listener {
+    // Stop accepting and wait for the accept loop to let go of the socket.
+    async fn close(self) -> Result {
+        self.stop.send_replace(false);
+        self.task.await?
+    }
+}
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Accept the next connection. Errors like running out of file descriptors
+// are logged and counted and accept is retried with an increasing delay so
+// the listener keeps going once the condition clears.
+async fn accept(listen: &TcpListener, errors: &AtomicU64) -> (TcpStream, SocketAddr, SocketAddr) {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let accepted = match listen.accept().await {
+            Ok((stream, peer_addr)) => stream
+                .local_addr()
+                .map(|local_addr| (stream, peer_addr, local_addr)),
+            Err(e) => Err(e),
+        };
+        match accepted {
+            Ok(a) => return a,
+            Err(e) => {
+                let count = errors.fetch_add(1, Ordering::Relaxed) + 1;
+                match listen.local_addr() {
+                    Ok(addr) => log::error!("Accept error on {} (#{}): {}", addr, count, e),
+                    Err(_) => log::error!("Accept error (#{}): {}", count, e),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
         }
-        Ok(())
     }
 }
 
@@ -222,3 +432,172 @@ async fn get_request(mut con: conn::Connection) -> Result<(conn::Connection, url
 
     Ok((con, url))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tokio_rustls::rustls;
+
+    fn vhosts() -> HashMap<String, Arc<config::ServerCfg>> {
+        let cfg: config::Config = toml::from_str(
+            r#"
+            interface = [ "127.0.0.1:1965" ]
+            [[server]]
+            hostname = "example.com"
+            dir = "/srv/gemini"
+            key = "key.pem"
+            cert = "cert.pem"
+            redirect = { "/a" = "/b", "/c" = "/d", "/e" = "/f" }
+            proxy = { "app" = "localhost:1966" }
+            scgi = { "/scgi" = "localhost:4000" }
+            [[server]]
+            hostname = "example.org"
+            dir = "/srv/gemini"
+            key = "key.pem"
+            cert = "cert.pem"
+            "#,
+        )
+        .unwrap();
+        cfg.to_map(&config::Shared::default())
+    }
+
+    // No handshake is made in these tests so no certificate is needed.
+    struct NoCerts;
+
+    impl rustls::server::ResolvesServerCert for NoCerts {
+        fn resolve(
+            &self,
+            _: rustls::server::ClientHello,
+        ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+            None
+        }
+    }
+
+    fn snapshot(cmap: HashMap<String, Arc<config::ServerCfg>>) -> Snapshot {
+        let tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCerts));
+        Snapshot {
+            cmap,
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+        }
+    }
+
+    async fn nothing(_: conn::Connection, _: url::Url) -> Result {
+        Ok(())
+    }
+
+    // A connection holding the old snapshot keeps its config across a swap
+    // while new connections get the new one. benches/connection_config.rs
+    // measures what the lookup costs.
+    #[test]
+    fn swap_keeps_old_snapshots() {
+        let server = Server::new(snapshot(vhosts()), force_boxed(nothing), Arc::default());
+        let old = server.snapshot.load_full();
+        let mut cmap = vhosts();
+        cmap.remove("example.com");
+        server.swap(snapshot(cmap));
+        assert!(old.vhost("example.com").is_some());
+        assert!(server.snapshot.load().vhost("example.com").is_none());
+    }
+
+    // A listener expecting PROXY protocol closes connections whose header is
+    // missing or broken without starting a handshake.
+    #[tokio::test]
+    async fn proxy_protocol_drops_bad_headers() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut server = Server::new(snapshot(vhosts()), force_boxed(nothing), Arc::default());
+        let interface = config::Interface {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            proxy_protocol: true,
+        };
+        server.listen_on(vec![interface]).await.unwrap();
+        let listener = server.listeners.values().next().unwrap();
+        let addr = listener.listen.local_addr().unwrap();
+
+        // The start of a ClientHello would leave the handshake waiting for
+        // the rest so the connection only closes if the header is refused.
+        let bad: [(&[u8], bool); 3] = [
+            (b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00", false),
+            (b"PROXY TCP4 nonsense\r\n\x16\x03\x01\x02\x00", false),
+            // A truncated v2 header is only noticed once the client is done.
+            (b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00", true),
+        ];
+        for (sent, done) in bad {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(sent).await.unwrap();
+            if done {
+                client.shutdown().await.unwrap();
+            }
+            let mut buf = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buf));
+            // Closing with unread bytes resets the connection.
+            let closed = match read.await.unwrap() {
+                Ok(n) => n == 0,
+                Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+            };
+            assert!(closed, "{:?}", sent);
+        }
+        server.shutdown(Duration::ZERO).await.unwrap();
+    }
+
+    // Lowering the descriptor limit affects the whole process so the test
+    // runs itself again in a child process.
+    #[test]
+    fn accept_recovers_from_emfile() {
+        if std::env::var_os("GEMSERV_EMFILE_CHILD").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "lib::server::tests::accept_recovers_from_emfile"])
+                .env("GEMSERV_EMFILE_CHILD", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let listen = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listen.local_addr().unwrap();
+        let errors = Arc::new(AtomicU64::new(0));
+
+        let task_errors = errors.clone();
+        let (send, recv) = std::sync::mpsc::channel();
+        rt.spawn(async move {
+            loop {
+                let (stream, _, _) = accept(&listen, &task_errors).await;
+                let stream = stream.into_std().unwrap();
+                send.send(stream).unwrap();
+            }
+        });
+
+        let limit = libc::rlimit {
+            rlim_cur: 256,
+            rlim_max: 256,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        let mut filler = Vec::new();
+        while let Ok(f) = std::fs::File::open("/dev/null") {
+            filler.push(f);
+        }
+
+        // Free a single descriptor for the client. The kernel queues the
+        // connection but the listener has nothing left to accept it with.
+        filler.pop();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(errors.load(Ordering::Relaxed) > 0);
+        assert!(recv.try_recv().is_err());
+
+        drop(filler);
+        let mut accepted = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        std::io::Write::write_all(&mut accepted, b"ok").unwrap();
+        drop(accepted);
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "ok");
+    }
+}
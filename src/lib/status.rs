@@ -28,6 +28,34 @@ pub enum Status {
 }
 
 impl Status {
+    pub fn from_u8(code: u8) -> Option<Status> {
+        let stat = match code {
+            10 => Status::Input,
+            20 => Status::Success,
+            21 => Status::SuccessEndOfSession,
+            30 => Status::RedirectTemporary,
+            31 => Status::RedirectPermanent,
+            40 => Status::TemporaryFailure,
+            41 => Status::ServerUnavailable,
+            42 => Status::CGIError,
+            43 => Status::ProxyError,
+            44 => Status::SlowDown,
+            50 => Status::PermanentFailure,
+            51 => Status::NotFound,
+            52 => Status::Gone,
+            53 => Status::ProxyRequestRefused,
+            59 => Status::BadRequest,
+            60 => Status::ClientCertificateRequired,
+            61 => Status::TransientCertificateRequested,
+            62 => Status::AuthorisedCertificateRequired,
+            63 => Status::CertificateNotAccepted,
+            64 => Status::FutureCertificateRejected,
+            65 => Status::ExpiredCertificateRejected,
+            _ => return None,
+        };
+        Some(stat)
+    }
+
     pub fn to_str(self) -> &'static str {
         match self {
             Status::Input => "Input",
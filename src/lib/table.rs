@@ -0,0 +1,63 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+// A map shared between connections that's kept from growing without bound.
+// Once it holds more than LIMIT entries the ones the caller doesn't keep are
+// dropped before it's used again.
+#[derive(Debug)]
+pub struct Table<K, V, const LIMIT: usize = 10_000> {
+    map: Mutex<HashMap<K, V>>,
+}
+
+impl<K, V, const LIMIT: usize> Default for Table<K, V, LIMIT> {
+    fn default() -> Self {
+        Table {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, const LIMIT: usize> Table<K, V, LIMIT> {
+    pub fn with<R>(
+        &self,
+        keep: impl FnMut(&K, &mut V) -> bool,
+        f: impl FnOnce(&mut HashMap<K, V>) -> R,
+    ) -> R {
+        let mut map = self.map.lock().unwrap();
+        if map.len() > LIMIT {
+            map.retain(keep);
+        }
+        f(&mut map)
+    }
+
+    pub fn clear(&self) {
+        self.map.lock().unwrap().clear();
+    }
+}
+
+// Values stored along with a stamp, like the mtime of the file they were read
+// from, so they're only used while the stamp still matches. Nothing tells
+// which entries are still wanted so a full table is emptied.
+impl<K: Eq + Hash, S: PartialEq, V: Clone, const LIMIT: usize> Table<K, (S, V), LIMIT> {
+    pub fn fresh<Q>(&self, key: &Q, stamp: &S) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.map.lock().unwrap().get(key) {
+            Some((s, v)) if s == stamp => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn store(&self, key: K, stamp: S, value: V) {
+        self.with(
+            |_, _| false,
+            |map| {
+                map.insert(key, (stamp, value));
+            },
+        )
+    }
+}
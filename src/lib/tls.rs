@@ -27,32 +27,43 @@ pub fn tls_acceptor_conf(cfg: config::Config) -> io::Result<TlsAcceptor> {
     Ok(acceptor)
 }
 
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
 pub fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+    let file = File::open(path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+    let certs: Vec<Certificate> = certs(&mut BufReader::new(file))
+        .map_err(|_| invalid(format!("{}: invalid cert", path)))
+        .map(|mut certs| certs.drain(..).map(Certificate).collect())?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificates found", path)));
+    }
+    Ok(certs)
 }
 
-fn load_key(path: &str) -> io::Result<Vec<PrivateKey>> {
-    pkcs8_private_keys(&mut std::io::BufReader::new(std::fs::File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let file = File::open(path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+    pkcs8_private_keys(&mut BufReader::new(file))
+        .map_err(|_| invalid(format!("{}: invalid key", path)))?
+        .drain(..)
+        .map(PrivateKey)
+        .next()
+        .ok_or_else(|| invalid(format!("{}: no pkcs8 private key found", path)))
 }
 
 fn load_keypair(cfg: config::Config) -> io::Result<ResolvesServerCertUsingSni> {
     let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
 
     for server in cfg.server.iter() {
-        let key = load_key(&server.key)?.remove(0);
+        let key = load_key(&server.key)?;
         let certs = load_certs(&server.cert)?;
-        let signing_key = sign::any_supported_type(&key).expect("error loading key");
+        let signing_key = sign::any_supported_type(&key)
+            .map_err(|_| invalid(format!("{}: unsupported key type", server.key)))?;
 
         resolver
-            .add(
-                &server.hostname.clone(),
-                CertifiedKey::new(certs, signing_key),
-            )
-            .expect("error loading key");
+            .add(&server.hostname, CertifiedKey::new(certs, signing_key))
+            .map_err(|e| invalid(format!("{}: {}", server.hostname, e)))?;
     }
     Ok(resolver)
 }
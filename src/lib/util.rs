@@ -1,4 +1,5 @@
 use sha2::Digest;
+use std::collections::HashMap;
 use url::form_urlencoded;
 
 pub fn url_decode(url: &[u8]) -> String {
@@ -8,9 +9,52 @@ pub fn url_decode(url: &[u8]) -> String {
     decoded
 }
 
+// The URL's path decoded the way it's looked up on disk, with empty and dot
+// segments resolved. Path rules match against this so encoding a character or
+// padding the path with extra slashes can't get around them.
+pub fn rule_path(url: &url::Url) -> String {
+    clean_path(&url_decode(url.path().as_bytes()))
+}
+
+// Resolve empty and dot segments in an already decoded path. A trailing slash
+// is kept when the last segment was one of them.
+pub fn clean_path(decoded: &str) -> String {
+    let mut segments = Vec::new();
+    let mut dir = false;
+    for s in decoded.split('/') {
+        dir = matches!(s, "" | "." | "..");
+        match s {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if dir && !segments.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+// The rule with the longest prefix of path, a path from rule_path. A slash is
+// added to the end so a rule for /dir/ also covers /dir.
+pub fn path_rule<'a, T>(
+    rules: Option<&'a HashMap<String, T>>,
+    path: &str,
+) -> Option<(&'a str, &'a T)> {
+    let path = format!("{}/", path);
+    rules?
+        .iter()
+        .filter(|(p, _)| path.starts_with(p.as_str()))
+        .max_by_key(|(p, _)| p.len())
+        .map(|(p, r)| (p.as_str(), r))
+}
+
 pub fn fingerhex(x509: &[u8]) -> String {
     let mut finger = sha2::Sha256::new();
-    finger.update(&x509);
+    finger.update(x509);
     let finger = finger.finalize();
     let mut hex: String = String::from("SHA256:");
     for f in finger {
@@ -18,3 +62,92 @@ pub fn fingerhex(x509: &[u8]) -> String {
     }
     hex
 }
+
+// Format seconds since the epoch as a UTC date like 2022-02-09.
+pub fn date(secs: u64) -> String {
+    // Howard Hinnant's civil_from_days.
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+// Days between the epoch and a UTC date, the reverse of date.
+pub fn days(y: i64, m: i64, d: i64) -> i64 {
+    // Howard Hinnant's days_from_civil.
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Match name against a pattern where * matches any run of characters and ?
+// matches a single one.
+pub fn glob(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    let mut star = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str) -> Option<String> {
+        let rules: HashMap<String, ()> = ["/", "/private/", "/private/open/", "/page"]
+            .iter()
+            .map(|p| (p.to_string(), ()))
+            .collect();
+        let url = url::Url::parse(&format!("gemini://example.com{}", path)).unwrap();
+        path_rule(Some(&rules), &rule_path(&url)).map(|(p, _)| p.to_string())
+    }
+
+    #[test]
+    fn path_rules_match_encoded_paths() {
+        for path in [
+            "/private/secret.gmi",
+            "/private",
+            "/%70rivate/secret.gmi",
+            "/%70%72%69%76%61%74%65/",
+            "//private/secret.gmi",
+            "/%2e/private/secret.gmi",
+            "/public/%2e%2e/private/secret.gmi",
+        ] {
+            assert_eq!(rule(path).as_deref(), Some("/private/"), "{}", path);
+        }
+        assert_eq!(
+            rule("/private/open/x.gmi").as_deref(),
+            Some("/private/open/")
+        );
+        assert_eq!(rule("/page.gmi").as_deref(), Some("/page"));
+        assert_eq!(rule("/privateer.gmi").as_deref(), Some("/"));
+        assert_eq!(rule("/public/private/").as_deref(), Some("/"));
+        assert_eq!(path_rule::<()>(None, "/private"), None);
+    }
+}
@@ -1,81 +1,211 @@
+#![allow(special_module_name)]
 #[macro_use]
 extern crate serde_derive;
 
+mod access;
+mod ban;
+mod cache;
 #[cfg(any(feature = "cgi", feature = "scgi"))]
 mod cgi;
 mod con_handler;
 mod config;
+mod dirlist;
+mod feed;
+mod include;
 mod lib;
 mod logger;
+#[cfg(feature = "markdown")]
+mod markdown;
+mod overrides;
+mod ratelimit;
 #[cfg(feature = "proxy")]
 mod revproxy;
+mod template;
+mod vfs;
 
 use lib::conn;
 use lib::errors;
+use lib::proxy_protocol;
 use lib::server;
 use lib::status;
+use lib::table;
 use lib::tls::{self, tls_acceptor_conf};
 use lib::util;
 
+use std::time::Duration;
 use tokio::signal::unix;
 use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
 
-async fn run(mut recv: watch::Receiver<bool>) -> errors::Result {
-    loop {
-        let cfg = match config::Config::new().await {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Config error: {}", e);
-                return Ok(());
-            }
-        };
+// Seconds to wait for connections to finish on shutdown.
+const DRAIN_TIMEOUT: u64 = 10;
 
-        // This will error because log init only wants to be called once.
-        // On reload it will allow going from higher to lower logging levels
-        // however trying to go from a lower lever to higher won't change.
-        if let Err(_) = logger::init(&cfg.log) {}
+// Load the config and everything it points to so a broken config is caught
+// before anything is torn down.
+async fn load() -> errors::Result<(config::Config, TlsAcceptor)> {
+    let cfg = config::Config::new().await?;
+    let acceptor = tls_acceptor_conf(cfg.clone())?;
+    Ok((cfg, acceptor))
+}
 
-        let cmap = cfg.to_map();
-        log::info!("Serving {} vhosts", cfg.server.len());
+fn apply(
+    cfg: &config::Config,
+    acceptor: TlsAcceptor,
+    shared: &config::Shared,
+) -> (Vec<config::Interface>, server::Snapshot) {
+    // This will error because log init only wants to be called once.
+    // On reload it will allow going from higher to lower logging levels
+    // however trying to go from a lower lever to higher won't change.
+    let _ = logger::init(&cfg.log);
 
-        let mut addr: Vec<std::net::SocketAddr> = Vec::new();
-        if let Some(i) = &cfg.interface {
-            addr.append(&mut i.to_owned());
+    // What was cached depends on the config it was made with.
+    shared.clear_caches();
+    let cmap = cfg.to_map(shared);
+    log::info!("Serving {} vhosts", cfg.server.len());
+    for srv in &cfg.server {
+        log_commits(srv);
+    }
+
+    let mut addr: Vec<config::Interface> = Vec::new();
+    if let Some(i) = &cfg.interface {
+        addr.append(&mut i.to_owned());
+    }
+    (addr, server::Snapshot { cmap, acceptor })
+}
+
+fn log_commits(srv: &config::Server) {
+    for (root, commit) in srv.vfs.commits() {
+        log::info!(
+            "{}: {} is at commit {}",
+            srv.hostname,
+            root.display(),
+            commit
+        );
+    }
+}
+
+// Returns once a git ref served by cfg moves, checking every git_poll seconds,
+// with the new files of the vhosts whose refs moved by their index in
+// cfg.server. Without git_poll it never returns.
+async fn git_moved(cfg: &config::Config) -> Vec<(usize, vfs::Vfs)> {
+    let poll = match cfg.git_poll {
+        Some(p) if p > 0 => Duration::from_secs(p),
+        _ => return std::future::pending().await,
+    };
+    loop {
+        tokio::time::sleep(poll).await;
+        let mut moved = Vec::new();
+        for (i, srv) in cfg.server.iter().enumerate() {
+            if let Some(v) = srv.vfs.refresh().await {
+                moved.push((i, v));
+            }
         }
+        if !moved.is_empty() {
+            return moved;
+        }
+    }
+}
+
+async fn run(mut reload: watch::Receiver<bool>, shared: config::Shared) -> errors::Result {
+    let (mut cfg, acceptor) = match load().await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return Ok(());
+        }
+    };
+    let mut drain = cfg.drain_timeout.unwrap_or(DRAIN_TIMEOUT);
+    let mut tls = acceptor.clone();
+    let (addr, snapshot) = apply(&cfg, acceptor, &shared);
+    let mut server = server::Server::new(
+        snapshot,
+        server::force_boxed(con_handler::handle_connection),
+        shared.bans.clone(),
+    );
+    server.listen_on(addr).await?;
 
-        let server = server::Server::bind(addr, tls_acceptor_conf, cfg).await?;
-        if let Err(e) = server
-            .serve(
-                cmap,
-                server::force_boxed(con_handler::handle_connection),
-                recv.clone(),
-            )
-            .await
-        {
-            return Err(e);
+    loop {
+        tokio::select! {
+            r = reload.changed() => {
+                r?;
+                if !*reload.borrow() {
+                    break;
+                }
+            }
+            moved = git_moved(&cfg) => {
+                // Only the files changed so the rest of the config, the
+                // listeners and the caches are left as they are.
+                for (i, v) in moved {
+                    cfg.server[i].vfs = v;
+                    log_commits(&cfg.server[i]);
+                }
+                server.swap(server::Snapshot {
+                    cmap: cfg.to_map(&shared),
+                    acceptor: tls.clone(),
+                });
+                continue;
+            }
+        }
+        // Keep serving the current config unless the new one loads cleanly.
+        let acceptor = match load().await {
+            Ok((c, a)) => {
+                cfg = c;
+                a
+            }
+            Err(e) => {
+                log::error!("Reload failed, keeping the old config: {}", e);
+                continue;
+            }
         };
-        recv.changed().await?;
+        drain = cfg.drain_timeout.unwrap_or(DRAIN_TIMEOUT);
+        tls = acceptor.clone();
+        let (addr, snapshot) = apply(&cfg, acceptor, &shared);
+        server.swap(snapshot);
+        if let Err(e) = server.listen_on(addr).await {
+            log::error!("Reload: {}", e);
+        }
     }
+
+    server.shutdown(Duration::from_secs(drain)).await
 }
 
-async fn signal_select(send: watch::Sender<bool>) -> errors::Result {
+// The channel carries whether the server should keep running. Sending true
+// reloads the config and false starts a graceful shutdown.
+async fn signal_select(send: watch::Sender<bool>, shared: config::Shared) -> errors::Result {
     let mut hangup = unix::signal(unix::SignalKind::hangup())?;
     let mut sigterm = unix::signal(unix::SignalKind::terminate())?;
+    let mut usr1 = unix::signal(unix::SignalKind::user_defined1())?;
+    let mut usr2 = unix::signal(unix::SignalKind::user_defined2())?;
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
+                // A second one skips waiting for connections.
+                if !*send.borrow() {
+                    std::process::exit(0);
+                }
                 log::info!("Received ctrl-c shutting down!");
                 send.send(false)?;
-                std::process::exit(0);
             },
             _ = sigterm.recv() => {
+                if !*send.borrow() {
+                    std::process::exit(0);
+                }
                 log::info!("Received SIGTERM shutting down");
                 send.send(false)?;
-                std::process::exit(0);
             },
             _ = hangup.recv() => {
+                if !*send.borrow() {
+                    continue;
+                }
                 log::info!("Received SIGHUP reloading config.");
-                send.send(false)?;
+                send.send(true)?;
+            }
+            _ = usr1.recv() => {
+                shared.bans.list();
+                shared.files.stats();
+            },
+            _ = usr2.recv() => {
+                shared.bans.clear();
             }
         }
     }
@@ -84,10 +214,12 @@ async fn signal_select(send: watch::Sender<bool>) -> errors::Result {
 #[tokio::main]
 async fn main() -> errors::Result {
     let (send, recv) = watch::channel(true);
+    let shared = config::Shared::default();
+    let signal_shared = shared.clone();
     tokio::spawn(async move {
-        signal_select(send).await?;
-        return Ok(()) as errors::Result;
+        signal_select(send, signal_shared).await?;
+        Ok(()) as errors::Result
     });
-    run(recv).await?;
+    run(recv, shared).await?;
     Ok(())
 }
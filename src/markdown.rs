@@ -0,0 +1,258 @@
+#![cfg(feature = "markdown")]
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
+
+use crate::config;
+use crate::table::Table;
+use crate::vfs::{Stat, Vfs};
+
+// Converted pages keyed by path along with the mtime of the markdown they
+// were converted from. Pages can be large so fewer are kept than titles.
+#[derive(Debug, Default)]
+pub struct Pages {
+    table: Table<PathBuf, (SystemTime, Arc<str>), 1_000>,
+}
+
+impl Pages {
+    pub fn clear(&self) {
+        self.table.clear();
+    }
+
+    pub async fn get(&self, vfs: &Vfs, path: &Path, st: &Stat) -> io::Result<Arc<str>> {
+        let modified = st.mtime.unwrap_or(SystemTime::UNIX_EPOCH);
+        if let Some(page) = self.table.fresh(path, &modified) {
+            return Ok(page);
+        }
+        let md = vfs.read(path).await?;
+        let page: Arc<str> = to_gemtext(&String::from_utf8_lossy(&md)).into();
+        self.table.store(path.to_path_buf(), modified, page.clone());
+        Ok(page)
+    }
+}
+
+fn is_markdown(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("md") | Some("markdown")
+    )
+}
+
+// Whether the file at path is sent converted to gemtext.
+pub fn convert(srv: &config::Server, path: &Path) -> bool {
+    srv.markdown.as_ref().is_some_and(|m| m.enabled) && is_markdown(path)
+}
+
+// If path is a markdown file with the raw suffix on the end strip the suffix
+// so the file is sent as is.
+pub fn raw(srv: &config::Server, path: &mut PathBuf) -> bool {
+    let md = match &srv.markdown {
+        Some(m) if m.enabled => m,
+        _ => return false,
+    };
+    let stripped = match path.to_str().and_then(|p| p.strip_suffix(md.raw.as_str())) {
+        Some(s) if is_markdown(Path::new(s)) => PathBuf::from(s),
+        _ => return false,
+    };
+    if srv.vfs.exists(path) || !srv.vfs.is_file(&stripped) {
+        return false;
+    }
+    *path = stripped;
+    true
+}
+
+#[derive(Default)]
+struct Writer {
+    out: String,
+    // Text of the block being built.
+    block: String,
+    // Links that are open along with where their text starts in block.
+    open: Vec<(String, usize)>,
+    // Links found in the block that go on their own lines after it.
+    links: Vec<(String, String)>,
+    heading: Option<usize>,
+    item: bool,
+    quote: usize,
+    lists: usize,
+    code: bool,
+}
+
+impl Writer {
+    fn flush(&mut self) {
+        let text = self.block.trim().to_string();
+        self.block.clear();
+        // A block that's nothing but a link becomes just the link line.
+        let only_link = self.links.len() == 1 && self.links[0].1.trim() == text;
+        if !text.is_empty() && !only_link {
+            for (i, line) in text.split('\n').enumerate() {
+                if self.quote > 0 {
+                    self.out.push_str("> ");
+                }
+                if let Some(level) = self.heading {
+                    self.out.push_str(&"#".repeat(level));
+                    self.out.push(' ');
+                } else if self.item && i == 0 {
+                    self.out.push_str("* ");
+                }
+                self.out.push_str(line.trim());
+                self.out.push('\n');
+            }
+        }
+        self.item = false;
+        for (url, label) in self.links.drain(..) {
+            let label = label.replace('\n', " ");
+            self.out.push_str(&format!("=> {} {}\n", url, label.trim()));
+        }
+    }
+
+    // Blocks are separated by an empty line, except for list items.
+    fn blank(&mut self) {
+        if self.lists == 0 && !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(Tag::Heading(level, _, _)) => {
+                self.flush();
+                self.heading = Some((level as usize).min(3));
+            }
+            Event::End(Tag::Heading(..)) => {
+                self.flush();
+                self.heading = None;
+                self.blank();
+            }
+            Event::End(Tag::Paragraph) => {
+                self.flush();
+                self.blank();
+            }
+            Event::Start(Tag::BlockQuote) => {
+                self.flush();
+                self.quote += 1;
+            }
+            Event::End(Tag::BlockQuote) => {
+                self.flush();
+                self.quote -= 1;
+                self.blank();
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                self.flush();
+                self.out.push_str("```");
+                if let CodeBlockKind::Fenced(lang) = kind {
+                    self.out.push_str(&lang);
+                }
+                self.out.push('\n');
+                self.code = true;
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if !self.out.ends_with('\n') {
+                    self.out.push('\n');
+                }
+                self.out.push_str("```\n");
+                self.code = false;
+                self.blank();
+            }
+            Event::Start(Tag::List(_)) => {
+                self.flush();
+                self.lists += 1;
+            }
+            Event::End(Tag::List(_)) => {
+                self.flush();
+                self.lists -= 1;
+                self.blank();
+            }
+            Event::Start(Tag::Item) => {
+                self.flush();
+                self.item = true;
+            }
+            Event::End(Tag::Item) => self.flush(),
+            Event::Start(Tag::Link(_, url, _)) | Event::Start(Tag::Image(_, url, _)) => {
+                self.open.push((url.to_string(), self.block.len()));
+            }
+            Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) => {
+                if let Some((url, start)) = self.open.pop() {
+                    let label = match self.block[start..].trim() {
+                        "" => url.clone(),
+                        l => l.to_string(),
+                    };
+                    self.links.push((url, label));
+                }
+            }
+            Event::Text(t) if self.code => self.out.push_str(&t),
+            Event::Text(t) => self.block.push_str(&t),
+            Event::Code(t) => {
+                self.block.push('`');
+                self.block.push_str(&t);
+                self.block.push('`');
+            }
+            Event::SoftBreak => self.block.push(' '),
+            Event::HardBreak => self.block.push('\n'),
+            Event::Rule => {
+                self.flush();
+                self.out.push_str("-----\n");
+                self.blank();
+            }
+            Event::TaskListMarker(done) => {
+                self.block.push_str(if done { "[x] " } else { "[ ] " });
+            }
+            _ => {}
+        }
+    }
+}
+
+// Convert markdown to gemtext. Links are moved to their own lines after the
+// block they're in since gemtext has no inline links.
+pub fn to_gemtext(md: &str) -> String {
+    let mut w = Writer::default();
+    for event in Parser::new(md) {
+        w.event(event);
+    }
+    w.flush();
+    let len = w.out.trim_end().len();
+    w.out.truncate(len);
+    w.out.push('\n');
+    w.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headings() {
+        assert_eq!(
+            to_gemtext("# One\n\n## Two\n\n#### Four\n\ntext"),
+            "# One\n\n## Two\n\n### Four\n\ntext\n"
+        );
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            to_gemtext("See [the site](https://example.com) and [docs](/docs/).\n\n[alone](/a.gmi)"),
+            "See the site and docs.\n=> https://example.com the site\n=> /docs/ docs\n\n=> /a.gmi alone\n"
+        );
+        assert_eq!(to_gemtext("![pic](/p.png)"), "=> /p.png pic\n");
+        assert_eq!(to_gemtext("[](/empty)"), "=> /empty /empty\n");
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            to_gemtext("* one\n* two [x](/x)\n  * nested\n\n1. first\n2. second\n\ntext"),
+            "* one\n* two x\n=> /x x\n* nested\n\n* first\n* second\n\ntext\n"
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            to_gemtext("```rust\nfn main() {}\n```\n\n    indented\n\nafter"),
+            "```rust\nfn main() {}\n```\n\n```\nindented\n```\n\nafter\n"
+        );
+    }
+}
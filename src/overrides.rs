@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::access;
+use crate::config;
+use crate::status::Status;
+use crate::table::Table;
+use crate::vfs::Vfs;
+
+type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// The file a directory's overrides are read from. It's never served or listed.
+pub const FILE: &str = ".gemserv.toml";
+
+// The settings a directory can change for itself and the directories below
+// it. Any other key makes the file an error so typos don't go unnoticed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    pub index: Option<config::Index>,
+    pub lang: Option<String>,
+    // File extension to the MIME type it's sent as.
+    pub mime: Option<HashMap<String, String>>,
+    pub dirlist: Option<config::DirList>,
+    // Paths relative to the directory and where they're redirected to.
+    pub redirect: Option<HashMap<String, String>>,
+    // Paths relative to the directory that get 52 Gone.
+    pub gone: Option<Vec<String>>,
+    pub allow: Option<Vec<access::Cidr>>,
+    pub deny: Option<Vec<access::Cidr>>,
+    pub status: Option<u8>,
+}
+
+async fn parse(vfs: &Vfs, path: &Path) -> Result<Override> {
+    let bytes = vfs.read(path).await?;
+    let o: Override = toml::from_str(&String::from_utf8_lossy(&bytes))?;
+    if let Some(c) = o.status.filter(|c| !access::valid_status(*c)) {
+        return Err(format!("status {} must be a 4x or 5x status", c).into());
+    }
+    Ok(o)
+}
+
+// Whether path is an override file.
+pub fn is_file(path: &Path) -> bool {
+    path.file_name() == Some(FILE.as_ref())
+}
+
+// What an override file parsed to. None if it's broken.
+type Parsed = Option<Arc<Override>>;
+
+// The mtime a file was read at and what it parsed to.
+type Entry = (Option<SystemTime>, Parsed);
+
+// Parsed override files keyed by path. A file that fails to parse is logged
+// once and remembered as broken until it changes.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    table: Table<PathBuf, Entry, 1_000>,
+}
+
+impl Overrides {
+    pub fn clear(&self) {
+        self.table.clear();
+    }
+
+    // None if there's no file at path.
+    async fn load(&self, vfs: &Vfs, path: &Path) -> Option<Parsed> {
+        let st = vfs.stat(path).await.ok().filter(|s| s.is_file())?;
+        if let Some(o) = self.table.fresh(path, &st.mtime) {
+            return Some(o);
+        }
+        let o = match parse(vfs, path).await {
+            Ok(o) => Some(Arc::new(o)),
+            Err(e) => {
+                log::error!("{}: {}", path.display(), e);
+                None
+            }
+        };
+        self.table.store(path.to_path_buf(), st.mtime, o.clone());
+        Some(o)
+    }
+
+    // The overrides for a request for rel inside the layers of a document
+    // root. They're read from the top of each layer and every directory down
+    // to the one rel is in, or rel itself if dir is set. At the same depth an
+    // earlier layer's file wins over a later one's.
+    pub async fn get(&self, vfs: &Vfs, roots: &[PathBuf], rel: &Path, dir: bool) -> Found {
+        let mut found = Found::default();
+        let mut here = PathBuf::new();
+        let mut rest: Vec<_> = rel.components().collect();
+        loop {
+            let remaining: PathBuf = rest.iter().collect();
+            for root in roots.iter().rev() {
+                match self.load(vfs, &root.join(&here).join(FILE)).await {
+                    Some(Some(o)) => found.levels.push((remaining.clone(), o)),
+                    Some(None) => found.broken = true,
+                    None => {}
+                }
+            }
+            if rest.is_empty() || (rest.len() == 1 && !dir) {
+                break;
+            }
+            here.push(rest.remove(0));
+        }
+        found
+    }
+}
+
+// The overrides that apply to a request, top directory first, each with the
+// part of the requested path below its directory.
+#[derive(Debug, Default)]
+pub struct Found {
+    levels: Vec<(PathBuf, Arc<Override>)>,
+    // Whether one of the files failed to parse.
+    broken: bool,
+}
+
+impl Found {
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty() && !self.broken
+    }
+
+    // The deepest directory that sets the field wins.
+    fn last<'a, T: ?Sized>(&'a self, f: impl Fn(&'a Override) -> Option<&'a T>) -> Option<&'a T> {
+        self.levels.iter().rev().find_map(|(_, o)| f(o))
+    }
+
+    pub fn index(&self) -> Option<&config::Index> {
+        self.last(|o| o.index.as_ref())
+    }
+
+    pub fn lang(&self) -> Option<&str> {
+        self.last(|o| o.lang.as_deref())
+    }
+
+    pub fn dirlist(&self) -> Option<&config::DirList> {
+        self.last(|o| o.dirlist.as_ref())
+    }
+
+    pub fn mime(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?;
+        self.last(|o| {
+            o.mime.as_ref()?.iter().find_map(|(e, m)| {
+                if e.trim_start_matches('.') == ext {
+                    Some(m.as_str())
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    // Access rules are taken from the deepest directory that has any. Returns
+    // the status to send if the client isn't allowed. A broken file could be
+    // meant to keep clients out so nobody is allowed until it's fixed.
+    pub fn check(&self, ip: IpAddr, fallback: Option<u8>) -> Option<Status> {
+        if self.broken {
+            return fallback
+                .and_then(Status::from_u8)
+                .or(Some(Status::TemporaryFailure));
+        }
+        let o = self.last(|o| {
+            if o.allow.is_some() || o.deny.is_some() {
+                Some(o)
+            } else {
+                None
+            }
+        })?;
+        if !access::denied(ip, &o.allow, &o.deny) {
+            return None;
+        }
+        o.status
+            .or(fallback)
+            .and_then(Status::from_u8)
+            .or(Some(Status::NotFound))
+    }
+
+    // Whether the path or a directory it's in is marked as gone.
+    pub fn gone(&self) -> bool {
+        self.levels.iter().any(|(rest, o)| {
+            o.gone.iter().flatten().any(|g| {
+                let g = Path::new(g.trim_matches('/'));
+                !g.as_os_str().is_empty() && rest.starts_with(g)
+            })
+        })
+    }
+
+    // Where the request is redirected to. Targets are relative to the URL of
+    // the directory the override file is in.
+    pub fn redirect(&self, url: &url::Url) -> Option<url::Url> {
+        self.levels.iter().rev().find_map(|(rest, o)| {
+            let target = o.redirect.as_ref()?.iter().find_map(|(from, to)| {
+                let from = Path::new(from.trim_matches('/'));
+                if !from.as_os_str().is_empty() && from == rest {
+                    Some(to)
+                } else {
+                    None
+                }
+            })?;
+            let mut ups = rest.components().count();
+            if !url.path().ends_with('/') {
+                ups -= 1;
+            }
+            let dir = url.join(&format!("./{}", "../".repeat(ups))).ok()?;
+            dir.join(target).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    // A fresh directory for a test's files.
+    fn tmp(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gemserv-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, toml: &str, age: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, toml).unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(age);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    fn found(levels: &[(&str, &str)]) -> Found {
+        let levels = levels
+            .iter()
+            .map(|(rest, toml)| (PathBuf::from(rest), Arc::new(toml::from_str(toml).unwrap())))
+            .collect();
+        Found {
+            levels,
+            broken: false,
+        }
+    }
+
+    fn redirect(f: &Found, url: &str) -> Option<String> {
+        let url = url::Url::parse(url).unwrap();
+        f.redirect(&url).map(|u| u.to_string())
+    }
+
+    #[test]
+    fn redirects_are_relative_to_the_override() {
+        let f = found(&[("a/b/old.gmi", r#"redirect = { "a/b/old.gmi" = "new.gmi" }"#)]);
+        assert_eq!(
+            redirect(&f, "gemini://example.com/a/b/old.gmi").as_deref(),
+            Some("gemini://example.com/new.gmi")
+        );
+
+        let toml = r#"redirect = { "old/" = "new/" }"#;
+        let f = found(&[("old", toml)]);
+        assert_eq!(
+            redirect(&f, "gemini://example.com/docs/old/").as_deref(),
+            Some("gemini://example.com/docs/new/")
+        );
+        assert_eq!(
+            redirect(&f, "gemini://example.com/docs/old").as_deref(),
+            Some("gemini://example.com/docs/new/")
+        );
+        let f = found(&[("old/page.gmi", toml)]);
+        assert_eq!(redirect(&f, "gemini://example.com/docs/old/page.gmi"), None);
+    }
+
+    #[test]
+    fn gone_covers_everything_below() {
+        let f = found(&[("drafts/post.gmi", r#"gone = [ "drafts/" ]"#)]);
+        assert!(f.gone());
+        assert!(found(&[("drafts", r#"gone = [ "drafts/" ]"#)]).gone());
+        assert!(!found(&[("draftsman.gmi", r#"gone = [ "drafts/" ]"#)]).gone());
+        assert!(!found(&[("", r#"gone = [ "drafts/" ]"#)]).gone());
+    }
+
+    #[tokio::test]
+    async fn deeper_and_earlier_files_win() {
+        let dir = tmp("overrides-win");
+        let (top, bottom) = (dir.join("top"), dir.join("bottom"));
+        write(&top.join(FILE), r#"lang = "en""#, 60);
+        write(&bottom.join(FILE), r#"lang = "de""#, 60);
+        write(&bottom.join("sub").join(FILE), r#"lang = "fr""#, 60);
+        let roots = [top, bottom];
+
+        let overrides = Overrides::default();
+        let vfs = Vfs::default();
+        let lang = |rel: &'static str, dir: bool| {
+            let (overrides, vfs, roots) = (&overrides, &vfs, &roots);
+            async move {
+                let f = overrides.get(vfs, roots, Path::new(rel), dir).await;
+                f.lang().map(str::to_string)
+            }
+        };
+        assert_eq!(lang("page.gmi", false).await.as_deref(), Some("en"));
+        assert_eq!(lang("sub", false).await.as_deref(), Some("en"));
+        assert_eq!(lang("sub", true).await.as_deref(), Some("fr"));
+        assert_eq!(lang("sub/page.gmi", false).await.as_deref(), Some("fr"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_read_again() {
+        let dir = tmp("overrides-reload");
+        let file = dir.join(FILE);
+        let roots = [dir.clone()];
+        let overrides = Overrides::default();
+        let vfs = Vfs::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let code = |f: &Found, fallback| f.check(ip, fallback).map(|s| s as u8);
+
+        write(&file, r#"lang = "en""#, 120);
+        let f = overrides.get(&vfs, &roots, Path::new("a.gmi"), false).await;
+        assert_eq!(f.lang(), Some("en"));
+        assert_eq!(code(&f, None), None);
+
+        // A broken file keeps everyone out until it's fixed.
+        write(&file, r#"lang = "en" bogus"#, 60);
+        let f = overrides.get(&vfs, &roots, Path::new("a.gmi"), false).await;
+        assert!(!f.is_empty());
+        assert_eq!(code(&f, None), Some(40));
+        assert_eq!(code(&f, Some(53)), Some(53));
+
+        write(&file, r#"lang = "de""#, 0);
+        let f = overrides.get(&vfs, &roots, Path::new("a.gmi"), false).await;
+        assert_eq!(f.lang(), Some("de"));
+        assert_eq!(code(&f, None), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Instant;
+
+use crate::config;
+use crate::conn;
+use crate::table::Table;
+use crate::util;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Addr(IpAddr),
+    Cert(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Limiter {
+    // Idle buckets are dropped once there are too many clients.
+    buckets: Table<(String, Key), Bucket>,
+}
+
+impl Limiter {
+    // Take a token from the client's bucket in scope. Returns the number of
+    // seconds until the client may retry if the bucket is empty.
+    fn take(&self, scope: &str, key: Key, rule: &config::RateLimit) -> Option<u64> {
+        let now = Instant::now();
+        let idle = |_: &_, b: &mut Bucket| {
+            b.refill(now);
+            b.tokens < b.burst
+        };
+        self.buckets.with(idle, |buckets| {
+            let bucket = buckets
+                .entry((scope.to_string(), key))
+                .or_insert_with(|| Bucket {
+                    tokens: rule.burst as f64,
+                    last: now,
+                    rate: rule.rate,
+                    burst: rule.burst as f64,
+                });
+            // The rule may have changed on reload.
+            bucket.rate = rule.rate;
+            bucket.burst = rule.burst as f64;
+            bucket.refill(now);
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return None;
+            }
+            let wait = (1.0 - bucket.tokens) / bucket.rate;
+            Some(wait.ceil().max(1.0) as u64)
+        })
+    }
+}
+
+// IPv6 clients usually get a whole /64 so they are limited as one.
+fn addr_key(addr: IpAddr) -> IpAddr {
+    match addr.to_canonical() {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+        v4 => v4,
+    }
+}
+
+fn client_key(con: &conn::Connection, rule: &config::RateLimit) -> Key {
+    if rule.cert.unwrap_or(false) {
+        let (_, session) = con.stream.get_ref();
+        if let Some(certs) = session.peer_certificates() {
+            return Key::Cert(util::fingerhex(certs[0].as_ref()));
+        }
+    }
+    Key::Addr(addr_key(con.peer_addr.ip()))
+}
+
+// Check the server wide limit and then the longest matching path limit.
+// Returns the number of seconds the client has to wait if it's limited.
+pub fn check(con: &conn::Connection, url: &url::Url) -> Option<u64> {
+    if let Some(rule) = &con.srv.ratelimit {
+        let key = client_key(con, rule);
+        if let Some(wait) = con.srv.limiter.take("", key, rule) {
+            return Some(wait);
+        }
+    }
+
+    let rules = con.srv.server.ratelimit_path.as_ref();
+    let (prefix, rule) = util::path_rule(rules, &util::rule_path(url))?;
+    let scope = format!("{}{}", con.srv.server.hostname, prefix);
+    let key = client_key(con, rule);
+    con.srv.limiter.take(&scope, key, rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rate: f64, burst: u32) -> config::RateLimit {
+        config::RateLimit {
+            rate,
+            burst,
+            cert: None,
+        }
+    }
+
+    fn addr(ip: &str) -> Key {
+        Key::Addr(addr_key(ip.parse().unwrap()))
+    }
+
+    #[test]
+    fn retry_delay() {
+        let limiter = Limiter::default();
+        let slow = rule(0.1, 2);
+        assert_eq!(limiter.take("", addr("192.0.2.1"), &slow), None);
+        assert_eq!(limiter.take("", addr("192.0.2.1"), &slow), None);
+        // An empty bucket gains a token every 10s.
+        assert_eq!(limiter.take("", addr("192.0.2.1"), &slow), Some(10));
+        // The wait is never under a second.
+        let fast = rule(100.0, 1);
+        assert_eq!(limiter.take("fast", addr("192.0.2.1"), &fast), None);
+        assert_eq!(limiter.take("fast", addr("192.0.2.1"), &fast), Some(1));
+        // Other clients and scopes have their own buckets.
+        assert_eq!(limiter.take("", addr("192.0.2.2"), &slow), None);
+        assert_eq!(limiter.take("/other", addr("192.0.2.1"), &slow), None);
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        assert_eq!(addr("2001:db8:1:2::1"), addr("2001:db8:1:2:ffff::9"));
+        assert_ne!(addr("2001:db8:1:2::1"), addr("2001:db8:1:3::1"));
+        assert_eq!(addr("::ffff:192.0.2.1"), addr("192.0.2.1"));
+        assert_ne!(addr("192.0.2.1"), addr("192.0.2.2"));
+
+        let limiter = Limiter::default();
+        let once = rule(0.1, 1);
+        assert_eq!(limiter.take("", addr("2001:db8:1:2::1"), &once), None);
+        assert!(limiter.take("", addr("2001:db8:1:2::2"), &once).is_some());
+    }
+}
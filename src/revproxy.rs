@@ -1,7 +1,7 @@
 #![cfg(feature = "proxy")]
 use std::convert::TryFrom;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -9,12 +9,35 @@ use tokio::net::TcpStream;
 use tokio_rustls::rustls;
 use tokio_rustls::TlsConnector;
 
+use crate::config;
 use crate::conn;
 use crate::logger;
+use crate::proxy_protocol;
 use crate::status::Status;
 use crate::tls;
 
-pub async fn proxy(addr: String, u: url::Url, mut con: conn::Connection) -> Result<(), io::Error> {
+// Connect to the upstream and send it a PROXY protocol header if it wants one.
+async fn connect<A: tokio::net::ToSocketAddrs>(
+    addr: A,
+    version: Option<proxy_protocol::Version>,
+    src: SocketAddr,
+    dst: SocketAddr,
+) -> Result<TcpStream, io::Error> {
+    let mut stream = TcpStream::connect(addr).await?;
+    if let Some(v) = version {
+        stream
+            .write_all(&proxy_protocol::header(v, src, dst))
+            .await?;
+    }
+    Ok(stream)
+}
+
+pub async fn proxy(
+    pr: &config::Proxy,
+    u: url::Url,
+    mut con: conn::Connection,
+) -> Result<(), io::Error> {
+    let addr = &pr.addr;
     let p: Vec<&str> = u.path().trim_start_matches('/').splitn(2, '/').collect();
     if p.len() == 1 {
         logger::logger(con.peer_addr, Status::NotFound, u.as_str());
@@ -38,7 +61,7 @@ pub async fn proxy(addr: String, u: url::Url, mut con: conn::Connection) -> Resu
         .with_no_client_auth();
     let connector = TlsConnector::from(Arc::new(config));
 
-    let stream = TcpStream::connect(&addr).await?;
+    let stream = connect(&addr, pr.proxy_protocol, con.peer_addr, con.local_addr).await?;
 
     let domain = rustls::ServerName::try_from(domain.as_str())
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
@@ -56,11 +79,12 @@ pub async fn proxy(addr: String, u: url::Url, mut con: conn::Connection) -> Resu
 }
 
 pub async fn proxy_all(
-    addr: &str,
+    pr: &config::Proxy,
     u: url::Url,
     mut con: conn::Connection,
 ) -> Result<(), io::Error> {
-    let domain = addr.splitn(2, ':').next().unwrap();
+    let addr = pr.addr.as_str();
+    let domain = addr.split(':').next().unwrap();
 
     let config = rustls::ClientConfig::builder()
         .with_safe_defaults()
@@ -68,7 +92,7 @@ pub async fn proxy_all(
         .with_no_client_auth();
     let connector = TlsConnector::from(Arc::new(config));
 
-    let stream = TcpStream::connect(&addr).await?;
+    let stream = connect(addr, pr.proxy_protocol, con.peer_addr, con.local_addr).await?;
 
     let domain = rustls::ServerName::try_from(domain)
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
@@ -84,3 +108,98 @@ pub async fn proxy_all(
     con.send_stream(&mut stream).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Connect through a stand-in upstream and return what it saw: the
+    // addresses from the header and the bytes that followed it.
+    async fn upstream_sees(
+        version: proxy_protocol::Version,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> (Option<(SocketAddr, SocketAddr)>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let header = proxy_protocol::read_header(&mut stream).await.unwrap();
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).await.unwrap();
+            (header, rest)
+        });
+
+        let mut stream = connect(addr, Some(version), src, dst).await.unwrap();
+        stream.write_all(b"\x16\x03\x01").await.unwrap();
+        drop(stream);
+        upstream.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_v1_header() {
+        let src: SocketAddr = "192.0.2.7:40000".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.1:1965".parse().unwrap();
+        let (header, rest) = upstream_sees(proxy_protocol::Version::V1, src, dst).await;
+        assert_eq!(header, Some((src, dst)));
+        assert_eq!(rest, b"\x16\x03\x01");
+        assert_eq!(
+            proxy_protocol::header(proxy_protocol::Version::V1, src, dst),
+            b"PROXY TCP4 192.0.2.7 198.51.100.1 40000 1965\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_v2_header() {
+        let src: SocketAddr = "[2001:db8::7]:40000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::1]:1965".parse().unwrap();
+        let (header, rest) = upstream_sees(proxy_protocol::Version::V2, src, dst).await;
+        assert_eq!(header, Some((src, dst)));
+        assert_eq!(rest, b"\x16\x03\x01");
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        // Version 2 PROXY command, TCP over IPv6 and 36 bytes of addresses.
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07,
+        ]);
+        expected.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        ]);
+        expected.extend_from_slice(&[0x9c, 0x40, 0x07, 0xad]);
+        assert_eq!(
+            proxy_protocol::header(proxy_protocol::Version::V2, src, dst),
+            expected
+        );
+    }
+
+    #[test]
+    fn v2_header_bytes_for_ipv4() {
+        let src: SocketAddr = "192.0.2.7:40000".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.1:1965".parse().unwrap();
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        // Version 2 PROXY command, TCP over IPv4 and 12 bytes of addresses.
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        expected.extend_from_slice(&[192, 0, 2, 7, 198, 51, 100, 1]);
+        expected.extend_from_slice(&[0x9c, 0x40, 0x07, 0xad]);
+        assert_eq!(
+            proxy_protocol::header(proxy_protocol::Version::V2, src, dst),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn unmaps_dual_stack_addresses() {
+        let src: SocketAddr = "[::ffff:192.0.2.7]:40000".parse().unwrap();
+        let dst: SocketAddr = "[::ffff:198.51.100.1]:1965".parse().unwrap();
+        let (header, _) = upstream_sees(proxy_protocol::Version::V2, src, dst).await;
+        assert_eq!(
+            header,
+            Some((
+                "192.0.2.7:40000".parse().unwrap(),
+                "198.51.100.1:1965".parse().unwrap()
+            ))
+        );
+    }
+}
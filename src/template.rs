@@ -0,0 +1,141 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::util;
+
+// The longest path rule matching either the URL or the path of the file it
+// was served from wins over the server's fragments.
+fn fragments<'a>(
+    srv: &'a config::Server,
+    url: &url::Url,
+    served: &str,
+) -> Option<(&'a str, &'a str)> {
+    let rules = srv.template_path.as_ref();
+    let rule = [util::rule_path(url).as_str(), served]
+        .iter()
+        .filter_map(|p| util::path_rule(rules, p))
+        .max_by_key(|(p, _)| p.len());
+    let (header, footer) = match rule {
+        Some((_, r)) if !r.enabled => return None,
+        Some((_, r)) => (
+            r.header.as_ref().or(srv.header.as_ref()),
+            r.footer.as_ref().or(srv.footer.as_ref()),
+        ),
+        None => (srv.header.as_ref(), srv.footer.as_ref()),
+    };
+    if header.is_none() && footer.is_none() {
+        return None;
+    }
+    let load = |f: Option<&String>| match f {
+        Some(f) => srv.fragments.get(f).map_or("", |s| s.as_str()),
+        None => "",
+    };
+    Some((load(header), load(footer)))
+}
+
+fn fill(fragment: &str, srv: &config::Server, url: &url::Url, mtime: &str) -> String {
+    fragment
+        .replace("{path}", url.path())
+        .replace("{hostname}", &srv.hostname)
+        .replace("{mtime}", mtime)
+}
+
+// The header and footer to put around a gemtext page at url that was last
+// modified at mtime. served is the path the page was found at, which differs
+// from the URL's when an extension or index file was filled in. The header
+// always ends with a newline.
+pub fn wrap(
+    srv: &config::Server,
+    url: &url::Url,
+    served: &str,
+    mtime: Option<SystemTime>,
+) -> Option<(String, String)> {
+    let (header, footer) = fragments(srv, url, served)?;
+    let mtime = mtime
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(String::new(), |d| util::date(d.as_secs()));
+    let mut header = fill(header, srv, url, &mtime);
+    if !header.is_empty() && !header.ends_with('\n') {
+        header.push_str("\r\n");
+    }
+    Some((header, fill(footer, srv, url, &mtime)))
+}
+
+// Whether a page sent in charset can be wrapped. The fragments are UTF-8 so
+// pages in any other charset are sent as they are.
+pub fn fits(charset: &str) -> bool {
+    charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("utf8")
+}
+
+// Put the fragments around a generated page.
+pub fn apply_text(body: String, wrap: &(String, String)) -> String {
+    let (header, footer) = wrap;
+    let mut page = String::with_capacity(header.len() + body.len() + footer.len() + 2);
+    page.push_str(header);
+    page.push_str(&body);
+    if !footer.is_empty() && !body.is_empty() && !body.ends_with('\n') {
+        page.push_str("\r\n");
+    }
+    page.push_str(footer);
+    page
+}
+
+// Put the fragments around a page that's already in memory.
+pub fn apply(body: &[u8], wrap: &(String, String)) -> Vec<u8> {
+    let (header, footer) = wrap;
+    let mut page = Vec::with_capacity(header.len() + body.len() + footer.len() + 2);
+    page.extend_from_slice(header.as_bytes());
+    page.extend_from_slice(body);
+    if !footer.is_empty() && !body.is_empty() && !body.ends_with(b"\n") {
+        page.extend_from_slice(b"\r\n");
+    }
+    page.extend_from_slice(footer.as_bytes());
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_rules_win_over_the_server() {
+        let mut srv = config::example(
+            r#"
+            header = "header.gmi"
+            template_path = { "/raw.gmi" = false, "/gemlog/" = { footer = "footer.gmi" } }
+            "#,
+        )
+        .server
+        .remove(0);
+        for (name, body) in [("header.gmi", "# {path}"), ("footer.gmi", "{hostname}")] {
+            srv.fragments.insert(name.to_string(), body.to_string());
+        }
+        let wrapped = |path: &str| {
+            let url = url::Url::parse(&format!("gemini://example.com{}", path)).unwrap();
+            wrap(&srv, &url, &util::rule_path(&url), None)
+        };
+        assert!(wrapped("/raw.gmi").is_none());
+        // A rule for the file also covers the URL it was served under.
+        let url = url::Url::parse("gemini://example.com/raw").unwrap();
+        assert!(wrap(&srv, &url, "/raw.gmi", None).is_none());
+        let (header, footer) = wrapped("/index.gmi").unwrap();
+        assert_eq!(header, "# /index.gmi\r\n");
+        assert_eq!(footer, "");
+        let (header, footer) = wrapped("/gemlog/post.gmi").unwrap();
+        assert_eq!(header, "# /gemlog/post.gmi\r\n");
+        assert_eq!(footer, "example.com");
+    }
+
+    #[test]
+    fn apply_text_wraps_pages() {
+        let wrap = ("# Header\n".to_string(), "Footer\n".to_string());
+        assert_eq!(
+            apply_text("Body".to_string(), &wrap),
+            "# Header\nBody\r\nFooter\n"
+        );
+        assert_eq!(
+            apply_text("Body\n".to_string(), &wrap),
+            "# Header\nBody\nFooter\n"
+        );
+    }
+}
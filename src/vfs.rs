@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::Metadata;
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncRead;
+use tokio::process::Command;
+
+use crate::util;
+
+// Document roots starting with this are read from a tar or zip archive.
+pub const ARCHIVE: &str = "archive:";
+// Document roots starting with this are read from a ref in a git repository,
+// given like git:/srv/capsule.git#main.
+pub const GIT: &str = "git:";
+// The document root that serves the capsule built into the binary.
+pub const EMBEDDED: &str = "embedded:";
+
+#[cfg(feature = "embed")]
+mod embedded {
+    include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+}
+
+pub type Reader = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+// What's known about a file or directory in either backend.
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub dir: bool,
+    pub file: bool,
+    pub len: u64,
+    pub mtime: Option<SystemTime>,
+    pub mode: u32,
+    // Metadata of files on the local disk, which the file cache needs.
+    pub local: Option<Metadata>,
+}
+
+impl From<Metadata> for Stat {
+    fn from(m: Metadata) -> Self {
+        Stat {
+            dir: m.is_dir(),
+            file: m.is_file(),
+            len: m.len(),
+            mtime: m.modified().ok(),
+            mode: m.permissions().mode(),
+            local: Some(m),
+        }
+    }
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file
+    }
+}
+
+// File contents read from an archive or compiled into the binary.
+#[derive(Debug, Clone)]
+enum Data {
+    Owned(Arc<[u8]>),
+    Static(&'static [u8]),
+}
+
+impl AsRef<[u8]> for Data {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Data::Owned(d) => d,
+            Data::Static(d) => d,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    stat: Stat,
+    data: Data,
+}
+
+#[derive(Debug)]
+struct Git {
+    repo: String,
+    rev: String,
+    commit: String,
+}
+
+// An archive read into memory when the config loads so replacing the file
+// and reloading switches to the new one at once. A git ref is read the same
+// way from the tree of the commit it points to.
+#[derive(Debug)]
+pub struct Archive {
+    // The document root as written in the config. Paths inside the archive
+    // start with it.
+    root: PathBuf,
+    // Keyed by path inside the archive. The top directory is the empty path.
+    nodes: HashMap<PathBuf, Node>,
+    children: HashMap<PathBuf, Vec<String>>,
+    git: Option<Git>,
+}
+
+async fn git(repo: &str, args: &[&str]) -> io::Result<Vec<u8>> {
+    let out = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo)
+        .args(args)
+        .output()
+        .await?;
+    if !out.status.success() {
+        let err = String::from_utf8_lossy(&out.stderr);
+        return Err(io::Error::other(err.trim().to_string()));
+    }
+    Ok(out.stdout)
+}
+
+// The commit a ref points to right now.
+async fn resolve(repo: &str, rev: &str) -> io::Result<String> {
+    let out = git(
+        repo,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)],
+    )
+    .await?;
+    Ok(String::from_utf8_lossy(&out).trim().to_string())
+}
+
+// The repository and ref of a git document root. The ref defaults to HEAD.
+fn git_root(root: &str) -> (&str, &str) {
+    let root = root.trim_start_matches(GIT);
+    match root.rsplit_once('#') {
+        Some((repo, rev)) if !rev.is_empty() => (repo, rev),
+        Some((repo, _)) => (repo, "HEAD"),
+        None => (root, "HEAD"),
+    }
+}
+
+// An archive entry's path with ./ and leading slashes dropped, or None if it
+// tries to climb out of the archive.
+fn clean(path: &Path) -> Option<PathBuf> {
+    let mut clean = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(n) => clean.push(n),
+            Component::CurDir | Component::RootDir => {}
+            _ => return None,
+        }
+    }
+    Some(clean)
+}
+
+fn stat(dir: bool, len: u64, mtime: Option<SystemTime>, mode: u32) -> Stat {
+    Stat {
+        dir,
+        file: !dir,
+        len,
+        mtime,
+        mode,
+        local: None,
+    }
+}
+
+type Entries = Vec<(PathBuf, Stat, Data)>;
+
+fn read_tar(bytes: &[u8]) -> io::Result<Entries> {
+    let mut entries = Vec::new();
+    let mut archive = tar::Archive::new(bytes);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            continue;
+        }
+        let path = match clean(&entry.path()?) {
+            Some(p) => p,
+            None => continue,
+        };
+        let mode = entry.header().mode()?;
+        let mtime = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let st = stat(kind.is_dir(), data.len() as u64, Some(mtime), mode);
+        entries.push((path, st, Data::Owned(data.into())));
+    }
+    Ok(entries)
+}
+
+fn read_zip(bytes: &[u8]) -> io::Result<Entries> {
+    let mut entries = Vec::new();
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = match clean(Path::new(entry.name())) {
+            Some(p) => p,
+            None => continue,
+        };
+        let dir = entry.is_dir();
+        let mode = entry.unix_mode().unwrap_or(if dir { 0o755 } else { 0o644 });
+        // Zip times are local to whoever made the archive. They're taken as UTC.
+        let t = entry.last_modified();
+        let day = util::days(t.year().into(), t.month().into(), t.day().into());
+        let secs = day * 86400
+            + i64::from(t.hour()) * 3600
+            + i64::from(t.minute()) * 60
+            + i64::from(t.second());
+        let mtime = u64::try_from(secs)
+            .ok()
+            .map(|s| UNIX_EPOCH + Duration::from_secs(s));
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let st = stat(dir, data.len() as u64, mtime, mode);
+        entries.push((path, st, Data::Owned(data.into())));
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "embed")]
+fn read_embedded() -> io::Result<Entries> {
+    let mut entries = Vec::new();
+    for (path, dir, mode, mtime, data) in embedded::FILES {
+        let mtime = UNIX_EPOCH + Duration::from_secs(*mtime);
+        let st = stat(*dir, data.len() as u64, Some(mtime), *mode);
+        entries.push((PathBuf::from(path), st, Data::Static(data)));
+    }
+    Ok(entries)
+}
+
+#[cfg(not(feature = "embed"))]
+fn read_embedded() -> io::Result<Entries> {
+    Err(io::Error::other(
+        "gemserv was built without the embed feature",
+    ))
+}
+
+impl Archive {
+    async fn load(root: &str) -> io::Result<Archive> {
+        let (entries, modified, git) = if root == EMBEDDED {
+            (read_embedded()?, None, None)
+        } else if root.starts_with(GIT) {
+            let (repo, rev) = git_root(root);
+            let commit = resolve(repo, rev).await?;
+            let tar = self::git(repo, &["archive", "--format=tar", &commit]).await?;
+            let entries = read_tar(&tar)?;
+            // Every entry carries the commit's time.
+            let modified = entries.first().and_then(|e| e.1.mtime);
+            let git = Git {
+                repo: repo.to_string(),
+                rev: rev.to_string(),
+                commit,
+            };
+            (entries, modified, Some(git))
+        } else {
+            let file = root.trim_start_matches(ARCHIVE);
+            let bytes = fs::read(file).await?;
+            let modified = fs::metadata(file).await?.modified().ok();
+            let entries = if bytes.starts_with(b"PK\x03\x04") {
+                read_zip(&bytes)?
+            } else {
+                read_tar(&bytes)?
+            };
+            (entries, modified, None)
+        };
+
+        let mut archive = Archive {
+            root: PathBuf::from(root),
+            nodes: HashMap::new(),
+            children: HashMap::new(),
+            git,
+        };
+        archive.add_dir(PathBuf::new(), modified);
+        for (path, st, data) in entries {
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            // Not every archive has entries for its directories.
+            for dir in path.ancestors().skip(1) {
+                archive.add_dir(dir.to_path_buf(), modified);
+            }
+            archive.add_child(&path);
+            archive.nodes.insert(path, Node { stat: st, data });
+        }
+        Ok(archive)
+    }
+
+    fn add_dir(&mut self, path: PathBuf, mtime: Option<SystemTime>) {
+        if self.nodes.contains_key(&path) {
+            return;
+        }
+        self.add_child(&path);
+        self.nodes.insert(
+            path,
+            Node {
+                stat: stat(true, 0, mtime, 0o755),
+                data: Data::Static(&[]),
+            },
+        );
+    }
+
+    fn add_child(&mut self, path: &Path) {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(p), Some(n)) => (p, n.to_string_lossy().into_owned()),
+            _ => return,
+        };
+        if self.nodes.contains_key(path) {
+            return;
+        }
+        self.children
+            .entry(parent.to_path_buf())
+            .or_default()
+            .push(name);
+    }
+
+    fn node(&self, path: &Path) -> io::Result<&Node> {
+        let inside = path.strip_prefix(&self.root).ok().and_then(clean);
+        inside
+            .and_then(|p| self.nodes.get(&p))
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+// Whether a document root is read into memory rather than served from disk.
+pub fn in_memory(dir: &str) -> bool {
+    dir.starts_with(ARCHIVE) || dir.starts_with(GIT) || dir == EMBEDDED
+}
+
+// Where a vhost's files come from. Paths under one of its archive roots are
+// served from memory and everything else from the local disk.
+#[derive(Debug, Clone, Default)]
+pub struct Vfs {
+    archives: Vec<Arc<Archive>>,
+}
+
+impl Vfs {
+    // Read the archives among a server's document roots.
+    pub async fn load(dirs: &[String]) -> Result<Vfs, String> {
+        let mut archives = Vec::new();
+        for dir in dirs.iter().filter(|d| in_memory(d)) {
+            match Archive::load(dir).await {
+                Ok(a) => archives.push(Arc::new(a)),
+                Err(e) => return Err(format!("{}: {}", dir, e)),
+            }
+        }
+        Ok(Vfs { archives })
+    }
+
+    // The document roots read from git along with the commit being served.
+    pub fn commits(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.archives
+            .iter()
+            .filter_map(|a| Some((a.root.as_path(), a.git.as_ref()?.commit.as_str())))
+    }
+
+    // Read the git roots whose ref now points somewhere other than the commit
+    // being served again, keeping the other archives as they are. Returns None
+    // if no ref moved.
+    pub async fn refresh(&self) -> Option<Vfs> {
+        let mut moved = false;
+        let mut archives = Vec::with_capacity(self.archives.len());
+        for a in &self.archives {
+            let current = match &a.git {
+                Some(g) => match resolve(&g.repo, &g.rev).await {
+                    Ok(c) => c == g.commit,
+                    Err(e) => {
+                        log::warn!("{}#{}: {}", g.repo, g.rev, e);
+                        true
+                    }
+                },
+                None => true,
+            };
+            if current {
+                archives.push(a.clone());
+                continue;
+            }
+            match Archive::load(&a.root.to_string_lossy()).await {
+                Ok(new) => {
+                    moved = true;
+                    archives.push(Arc::new(new));
+                }
+                Err(e) => {
+                    log::warn!("{}: {}", a.root.display(), e);
+                    archives.push(a.clone());
+                }
+            }
+        }
+        if moved {
+            Some(Vfs { archives })
+        } else {
+            None
+        }
+    }
+
+    fn archive(&self, path: &Path) -> Option<&Archive> {
+        self.archives
+            .iter()
+            .find(|a| path.starts_with(&a.root))
+            .map(|a| a.as_ref())
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        match self.archive(path) {
+            Some(a) => a.node(path).is_ok(),
+            None => path.exists(),
+        }
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        match self.archive(path) {
+            Some(a) => a.node(path).is_ok_and(|n| n.stat.file),
+            None => path.is_file(),
+        }
+    }
+
+    pub fn is_dir(&self, path: &Path) -> bool {
+        match self.archive(path) {
+            Some(a) => a.node(path).is_ok_and(|n| n.stat.dir),
+            None => path.is_dir(),
+        }
+    }
+
+    // The path with dot segments and symlinks resolved. Archives don't keep
+    // symlinks so their paths are only cleaned up.
+    pub async fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let a = match self.archive(path) {
+            Some(a) => a,
+            None => return fs::canonicalize(path).await,
+        };
+        let mut clean = PathBuf::new();
+        for c in path.components() {
+            match c {
+                Component::ParentDir => {
+                    clean.pop();
+                }
+                Component::CurDir => {}
+                c => clean.push(c),
+            }
+        }
+        if !clean.starts_with(&a.root) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        a.node(&clean)?;
+        Ok(clean)
+    }
+
+    pub async fn stat(&self, path: &Path) -> io::Result<Stat> {
+        match self.archive(path) {
+            Some(a) => Ok(a.node(path)?.stat.clone()),
+            None => Ok(fs::metadata(path).await?.into()),
+        }
+    }
+
+    pub async fn open(&self, path: &Path) -> io::Result<Reader> {
+        match self.archive(path) {
+            Some(a) => {
+                let node = a.node(path)?;
+                if node.stat.dir {
+                    return Err(io::Error::other("Is a directory"));
+                }
+                Ok(Box::new(Cursor::new(node.data.clone())))
+            }
+            None => Ok(Box::new(fs::File::open(path).await?)),
+        }
+    }
+
+    pub async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.archive(path) {
+            Some(a) => Ok(a.node(path)?.data.as_ref().to_vec()),
+            None => fs::read(path).await,
+        }
+    }
+
+    // The names in a directory that are valid UTF-8 along with their stats.
+    pub async fn list(&self, path: &Path) -> io::Result<Vec<(String, Stat)>> {
+        let mut list = Vec::new();
+        if let Some(a) = self.archive(path) {
+            let dir = path.strip_prefix(&a.root).ok().and_then(clean);
+            let names = dir.as_ref().and_then(|d| Some((d, a.children.get(d)?)));
+            if let Some((dir, names)) = names {
+                for name in names {
+                    if let Some(n) = a.nodes.get(&dir.join(name)) {
+                        list.push((name.clone(), n.stat.clone()));
+                    }
+                }
+            } else if a.node(path)?.stat.file {
+                return Err(io::Error::other("Not a directory"));
+            }
+            return Ok(list);
+        }
+        let mut rd = fs::read_dir(path).await?;
+        while let Some(file) = rd.next_entry().await? {
+            let name = match file.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            list.push((name, file.metadata().await?.into()));
+        }
+        Ok(list)
+    }
+}